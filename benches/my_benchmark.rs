@@ -2,16 +2,11 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use ppknn::server::setup_with_data;
-use ppknn::{network::*, AsyncEncComparator, EncItem};
+use dyn_stack::DynStack;
+use ppknn::server::{setup_polymul_fft, setup_with_data};
+use ppknn::{network::*, AsyncEncComparator, EncItem, DEFAULT_PARAMS as PARAMS};
 use tfhe::shortint::prelude::*;
 
-const PARAMS: Parameters = Parameters {
-    message_modulus: MessageModulus(32),
-    carry_modulus: CarryModulus(1),
-    ..PARAM_MESSAGE_2_CARRY_3
-};
-
 fn pbs_benchmark(c: &mut Criterion) {
     let (client, server) = gen_keys(PARAMS);
     let ct = client.encrypt(1);
@@ -25,7 +20,7 @@ fn pbs_benchmark(c: &mut Criterion) {
 
 fn ks_benchmark(c: &mut Criterion) {
     let dist_mod = PARAMS.message_modulus.0 * 2;
-    let (client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64);
+    let (client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64).unwrap();
     let ct = client.key.encrypt(1);
 
     c.bench_function("ks", |b| {
@@ -35,6 +30,30 @@ fn ks_benchmark(c: &mut Criterion) {
     });
 }
 
+fn min_benchmark(c: &mut Criterion) {
+    let dist_mod = PARAMS.message_modulus.0 * 2;
+    let (client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64).unwrap();
+    let a = client.key.encrypt(1);
+    let b = client.key.encrypt(2);
+
+    // the FFT context comes from the thread-local pool after the first iteration,
+    // compare with `min_fresh_fft`, the difference has not been measured yet
+    c.bench_function("min", |bench| {
+        bench.iter(|| {
+            let _ = server.min(&a, &b);
+        });
+    });
+
+    // a new FFT context for every comparison, as it was done before the pool
+    c.bench_function("min_fresh_fft", |bench| {
+        bench.iter(|| {
+            let (fft, mut mem) = setup_polymul_fft(PARAMS);
+            let mut stack = DynStack::new(&mut mem);
+            let _ = server.min_with_fft(&a, &b, fft.as_view(), &mut stack);
+        });
+    });
+}
+
 fn network_bench(c: &mut Criterion) {
     let d = 16usize;
     // let k = 1usize;
@@ -50,7 +69,7 @@ fn network_bench(c: &mut Criterion) {
 
     let dist_mod = PARAMS.message_modulus.0 * 2;
     // data and labels not actually used if we just need to use the comparator
-    let (mut client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64);
+    let (mut client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64).unwrap();
     let server = Arc::new(RwLock::new(server));
     let cmp = AsyncEncComparator::new(server, PARAMS);
 
@@ -74,7 +93,19 @@ fn network_bench(c: &mut Criterion) {
             par_run_network(&network, cmp.clone(), &a_actual);
         });
     });
+
+    c.bench_function("enc batched network", |b| {
+        b.iter(|| {
+            par_run_network_batched(&network, cmp.clone(), &a_actual);
+        });
+    });
 }
 
-criterion_group!(benches, pbs_benchmark, ks_benchmark, network_bench);
+criterion_group!(
+    benches,
+    pbs_benchmark,
+    ks_benchmark,
+    min_benchmark,
+    network_bench
+);
 criterion_main!(benches);
@@ -5,13 +5,6 @@ use std::time::Instant;
 use clap::Parser;
 use ppknn::network::*;
 use ppknn::*;
-use tfhe::shortint::prelude::*;
-
-const PARAMS: Parameters = Parameters {
-    message_modulus: MessageModulus(32),
-    carry_modulus: CarryModulus(1),
-    ..PARAM_MESSAGE_2_CARRY_3
-};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about="run an encrypted sorting network", long_about = None)]
@@ -24,6 +17,16 @@ struct Cli {
 
     #[clap(long, default_value_t = false, help = "use trivial parallelization")]
     trivial: bool,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "bootstrap every level of the network as one batch"
+    )]
+    batched: bool,
+
+    #[clap(long, default_value = DEFAULT_PRESET, help = "name of the parameter preset")]
+    params: String,
 }
 
 fn main() {
@@ -38,12 +41,13 @@ fn main() {
     .iter()
     .collect();
     let network = load_network(pb.as_path()).unwrap();
+    let params = preset(&cli.params).unwrap_or_else(|e| panic!("{e}"));
 
-    let dist_mod = PARAMS.message_modulus.0 * 2;
+    let dist_mod = params.message_modulus.0 * 2;
     // data and labels not actually used if we just need to use the comparator
-    let (mut client, server) = setup_with_data(PARAMS, &vec![], &vec![], dist_mod as u64);
+    let (mut client, server) = setup_with_data(params, &vec![], &vec![], dist_mod as u64).unwrap();
     let server = Arc::new(RwLock::new(server));
-    let cmp = AsyncEncComparator::new(server, PARAMS);
+    let cmp = AsyncEncComparator::new(server, params);
 
     // just create dummy elements
     let actual = (0..d).map(|_| {
@@ -56,7 +60,9 @@ fn main() {
 
     // start the network
     let start = Instant::now();
-    if cli.trivial {
+    if cli.batched {
+        par_run_network_batched(&network, cmp.clone(), &a_actual);
+    } else if cli.trivial {
         par_run_network_trivial(&network, cmp.clone(), &a_actual);
     } else {
         par_run_network(&network, cmp.clone(), &a_actual);
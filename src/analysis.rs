@@ -0,0 +1,153 @@
+use crate::KnnError;
+use tfhe::shortint::Parameters;
+
+/// The smallest and the highest value of a feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl ValueRange {
+    pub fn new(min: u64, max: u64) -> Self {
+        assert!(min <= max);
+        Self { min, max }
+    }
+
+    /// The range of every column of `rows`, the rows must have the same length.
+    pub fn from_rows(rows: &[Vec<u64>]) -> Vec<ValueRange> {
+        let gamma = rows.first().map_or(0, |r| r.len());
+        (0..gamma)
+            .map(|i| {
+                let column = rows.iter().map(|r| r[i]);
+                ValueRange::new(column.clone().min().unwrap(), column.max().unwrap())
+            })
+            .collect()
+    }
+
+    /// The highest absolute difference between a value in `self` and a value in `other`.
+    pub fn max_diff(&self, other: &ValueRange) -> u64 {
+        self.max
+            .abs_diff(other.min)
+            .max(other.max.abs_diff(self.min))
+    }
+}
+
+/// Bound the squared distances before they are computed modulo the distance plaintext modulus,
+/// since a distance that wraps around makes a far row look near.
+/// The bound only depends on the value ranges of the model and the query, not on the keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DistanceAnalysis {
+    pub model_ranges: Vec<ValueRange>,
+    pub query_ranges: Vec<ValueRange>,
+}
+
+impl DistanceAnalysis {
+    /// Analyse `rows` where every feature of the query is in `query_range`.
+    pub fn new(rows: &[Vec<u64>], query_range: ValueRange) -> Self {
+        let model_ranges = ValueRange::from_rows(rows);
+        Self {
+            query_ranges: vec![query_range; model_ranges.len()],
+            model_ranges,
+        }
+    }
+
+    /// Analyse `rows` where the features of the query are between zero
+    /// and the highest value of the same feature in the model.
+    pub fn in_model_range(rows: &[Vec<u64>]) -> Self {
+        let model_ranges = ValueRange::from_rows(rows);
+        Self {
+            query_ranges: model_ranges
+                .iter()
+                .map(|r| ValueRange::new(0, r.max))
+                .collect(),
+            model_ranges,
+        }
+    }
+
+    /// The number of features.
+    pub fn gamma(&self) -> usize {
+        self.model_ranges.len()
+    }
+
+    /// The highest squared distance between a row and any query in the declared range.
+    pub fn max_distance(&self) -> u64 {
+        self.model_ranges
+            .iter()
+            .zip(&self.query_ranges)
+            .map(|(m, q)| m.max_diff(q))
+            .fold(0u64, |acc, d| acc.saturating_add(d.saturating_mul(d)))
+    }
+
+    /// The smallest distance modulus where every distance is in the lower half,
+    /// it is a power of two since the modulus is used to compute the scaling factor.
+    /// The comparisons compute `a - b + t/2`, so a distance in the upper half
+    /// wraps around and a far row looks near.
+    pub fn min_dist_modulus(&self) -> u64 {
+        2 * (self.max_distance() + 1).next_power_of_two()
+    }
+
+    /// Pick the `initial_modulus` for `setup_with_modulus`,
+    /// it is never smaller than the message modulus of `params`.
+    pub fn pick_dist_modulus(&self, params: Parameters) -> u64 {
+        self.min_dist_modulus().max(params.message_modulus.0 as u64)
+    }
+
+    /// Check that every distance is smaller than `dist_modulus / 2`.
+    pub fn check(&self, dist_modulus: u64) -> Result<(), KnnError> {
+        let max_distance = self.max_distance();
+        if max_distance >= dist_modulus / 2 {
+            return Err(KnnError::DistanceOverflow {
+                max_distance,
+                dist_modulus,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::TEST_PARAM;
+
+    #[test]
+    fn test_analysis() {
+        let rows = vec![vec![1, 0, 3u64], vec![2, 0, 5]];
+        assert_eq!(
+            ValueRange::from_rows(&rows),
+            vec![
+                ValueRange::new(1, 2),
+                ValueRange::new(0, 0),
+                ValueRange::new(3, 5)
+            ]
+        );
+
+        // the query is in the model range: 2^2 + 0 + 5^2
+        let analysis = DistanceAnalysis::in_model_range(&rows);
+        assert_eq!(analysis.gamma(), 3);
+        assert_eq!(analysis.max_distance(), 29);
+        assert_eq!(analysis.min_dist_modulus(), 64);
+        assert!(analysis.check(64).is_ok());
+        assert!(analysis.check(32).is_err());
+
+        // the query is in [0, 7]: 6^2 + 7^2 + 5^2
+        let analysis = DistanceAnalysis::new(&rows, ValueRange::new(0, 7));
+        assert_eq!(analysis.max_distance(), 110);
+        assert_eq!(analysis.min_dist_modulus(), 256);
+        assert_eq!(analysis.pick_dist_modulus(TEST_PARAM), 256);
+        assert!(analysis.check(256).is_ok());
+        assert!(matches!(
+            analysis.check(128),
+            Err(KnnError::DistanceOverflow {
+                max_distance: 110,
+                dist_modulus: 128
+            })
+        ));
+
+        // the message modulus is the lower bound
+        let analysis = DistanceAnalysis::new(&[vec![0u64]], ValueRange::new(0, 1));
+        assert_eq!(analysis.min_dist_modulus(), 4);
+        assert_eq!(analysis.pick_dist_modulus(TEST_PARAM), 32);
+    }
+}
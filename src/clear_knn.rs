@@ -1,5 +1,6 @@
 use crate::{BatcherSort, ClearComparator, ClearItem};
 use rand::prelude::SliceRandom;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 
 const BEST_MODEL_TRIES: usize = 10000;
@@ -123,6 +124,33 @@ pub fn run_knn(
     (distances[0..k].to_vec(), max_dist)
 }
 
+/// Find the indices of the `k` nearest rows in `model_vec`,
+/// this is the plaintext version of `KnnServer::search`.
+pub fn search(k: usize, model_vec: &[Vec<u64>], target: &[u64]) -> Vec<u64> {
+    let ids: Vec<_> = (0..model_vec.len() as u64).collect();
+    let (nearest, _) = run_knn(k, model_vec, &ids, target);
+    nearest.iter().map(|item| item.class).collect()
+}
+
+/// Run the k-NN regression algorithm, the output is the mean of the `k` nearest targets.
+pub fn run_knn_regression(
+    k: usize,
+    model_vec: &[Vec<u64>],
+    targets: &[u64],
+    target: &[u64],
+) -> (f64, u64) {
+    let (nearest, max_dist) = run_knn(k, model_vec, targets, target);
+    let values: Vec<_> = nearest.iter().map(|item| item.class).collect();
+    (mean(&values), max_dist)
+}
+
+pub fn mean(vs: &[u64]) -> f64 {
+    assert!(!vs.is_empty());
+    vs.iter().sum::<u64>() as f64 / vs.len() as f64
+}
+
+/// Compute the most frequent class, ties are broken in favour of the smallest class
+/// like in `KnnServer::majority`.
 pub fn majority(vs: &[u64]) -> u64 {
     assert!(!vs.is_empty());
     let max = vs
@@ -132,7 +160,60 @@ pub fn majority(vs: &[u64]) -> u64 {
             m
         })
         .into_iter()
-        .max_by_key(|(_, v)| *v)
+        .max_by_key(|(k, v)| (*v, Reverse(*k)))
+        .map(|(k, _)| k);
+    max.unwrap()
+}
+
+/// Compute the class with the highest sum of weights,
+/// every item votes for its class with `weight(item.value)`.
+/// Ties are broken in favour of the smallest class.
+/// This is the plaintext version of `KnnServer::weighted_majority`.
+pub fn weighted_majority(vs: &[ClearItem], weight: impl Fn(u64) -> u64) -> u64 {
+    assert!(!vs.is_empty());
+    let max = vs
+        .iter()
+        .fold(HashMap::<u64, u64>::new(), |mut m, x| {
+            *m.entry(x.class).or_default() += weight(x.value);
+            m
+        })
+        .into_iter()
+        .max_by_key(|(k, v)| (*v, Reverse(*k)))
         .map(|(k, _)| k);
     max.unwrap()
 }
+
+/// Compute the class probabilities of the labels in `vs`,
+/// this is the plaintext version of `KnnClient::decrypt_histogram`.
+pub fn histogram(vs: &[u64], n_classes: usize) -> Vec<f64> {
+    assert!(!vs.is_empty());
+    let mut counts = vec![0usize; n_classes];
+    for v in vs {
+        counts[*v as usize] += 1;
+    }
+    counts
+        .into_iter()
+        .map(|c| c as f64 / vs.len() as f64)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vote_ties() {
+        assert_eq!(majority(&[3, 1, 1, 3, 2]), 1);
+        assert_eq!(majority(&[2, 0, 2, 0]), 0);
+
+        let items = vec![
+            ClearItem { value: 4, class: 3 },
+            ClearItem { value: 1, class: 2 },
+            ClearItem { value: 4, class: 1 },
+        ];
+        assert_eq!(weighted_majority(&items, |_| 1), 1);
+        // classes 1 and 3 have the same weight
+        assert_eq!(weighted_majority(&items, |d| if d < 2 { 1 } else { 2 }), 1);
+        assert_eq!(weighted_majority(&items, |d| if d < 2 { 2 } else { 1 }), 2);
+    }
+}
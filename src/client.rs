@@ -1,3 +1,5 @@
+use crate::{KnnError, RegressionOutput};
+use serde::{Deserialize, Serialize};
 use tfhe::core_crypto::algorithms::*;
 use tfhe::core_crypto::prelude::*;
 use tfhe::shortint::ciphertext::Degree;
@@ -28,7 +30,88 @@ pub struct KnnClient {
     pub dist_delta: u64,
 }
 
+/// The public keys that the server needs to run k-NN on behalf of the client.
+/// It does not contain any secret, see `KnnClient::keygen`
+/// and `KnnServer::from_evaluation_keys`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EvaluationKeys {
+    pub server_key: ServerKey,
+    pub lwe_to_glwe_ksk: LwePrivateFunctionalPackingKeyswitchKeyOwned<u64>,
+    pub dist_delta: u64,
+    pub params: Parameters,
+}
+
+impl EvaluationKeys {
+    /// The plaintext modulus of the distances, i.e., `dist_modulus` in `KnnClient::keygen`.
+    /// The keys may come from an untrusted client, so an error is returned unless
+    /// the moduli are powers of two, `dist_delta` is even and larger than the message modulus
+    /// and the distance modulus is at least the message modulus.
+    pub fn dist_modulus(&self) -> Result<u64, KnnError> {
+        let message_modulus = self.params.message_modulus.0 as u64;
+        let carry_modulus = self.params.carry_modulus.0 as u64;
+        let err = || KnnError::InvalidEvaluationKeys {
+            dist_delta: self.dist_delta,
+            message_modulus,
+            carry_modulus,
+        };
+        if !message_modulus.is_power_of_two()
+            || !carry_modulus.is_power_of_two()
+            || self.dist_delta % 2 != 0
+            || self.dist_delta <= message_modulus
+        {
+            return Err(err());
+        }
+        self.dist_delta
+            .checked_mul(carry_modulus)
+            .filter(|d| d.is_power_of_two())
+            .map(|d| (1u64 << 63) / d)
+            .filter(|&dist_modulus| dist_modulus >= message_modulus)
+            .ok_or_else(err)
+    }
+}
+
 impl KnnClient {
+    /// Create a client from an existing key, e.g., one that is loaded from disk,
+    /// the encryption RNG is seeded again.
+    pub fn from_key(key: ClientKey, dist_delta: u64) -> KnnClient {
+        let mut seeder = new_seeder();
+        let encryption_rng = EncryptionRandomGenerator::<ActivatedRandomGenerator>::new(
+            seeder.seed(),
+            seeder.as_mut(),
+        );
+        KnnClient {
+            params: key.parameters,
+            key,
+            encryption_rng,
+            dist_delta,
+        }
+    }
+
+    /// Generate all the keys on the client side, where the distances are computed
+    /// with the plaintext modulus `dist_modulus`.
+    /// The client key stays with the client and only the evaluation keys
+    /// should be sent to the server.
+    pub fn keygen(params: Parameters, dist_modulus: u64) -> (KnnClient, EvaluationKeys) {
+        assert!(dist_modulus.is_power_of_two());
+
+        let (client_key, server_key) = gen_keys(params);
+        let dist_delta = (1u64 << 63) / (dist_modulus * params.carry_modulus.0 as u64);
+        assert_eq!(dist_delta % 2, 0);
+        assert!((params.message_modulus.0 as u64) < dist_delta);
+
+        let mut client = KnnClient::from_key(client_key, dist_delta);
+        let lwe_to_glwe_ksk = gen_ksk(&client.key, &mut client.encryption_rng);
+        (
+            client,
+            EvaluationKeys {
+                server_key,
+                lwe_to_glwe_ksk,
+                dist_delta,
+                params,
+            },
+        )
+    }
+
     pub fn lwe_encrypt_with_delta(&mut self, x: u64, delta: u64) -> Ciphertext {
         let sk = self.key.get_lwe_sk_ref();
         let pt = Plaintext(x * delta);
@@ -108,6 +191,47 @@ impl KnnClient {
         let c2 = self.lwe_encrypt_with_delta(pt2, delta);
         (c, c2)
     }
+
+    /// Decrypt a value that is split into several blocks, e.g., a multi-block label,
+    /// the digits are in base message modulus with the least significant first.
+    pub fn decrypt_blocks(&self, blocks: &[Ciphertext]) -> u64 {
+        let base = self.params.message_modulus.0 as u64;
+        blocks
+            .iter()
+            .rev()
+            .fold(0, |acc, ct| acc * base + self.key.decrypt(ct))
+    }
+
+    /// Decrypt a record id from `KnnServer::search`.
+    pub fn decrypt_id(&self, ids: &[Ciphertext]) -> u64 {
+        self.decrypt_blocks(ids)
+    }
+
+    /// Decode the output of `KnnServer::regression` over `k` neighbours into the mean.
+    pub fn decode_regression(&self, ct: &Ciphertext, k: usize, output: RegressionOutput) -> f64 {
+        let x = self.key.decrypt(ct) as f64;
+        match output {
+            RegressionOutput::ExactMean => x / k as f64,
+            RegressionOutput::RoundedMean => x,
+        }
+    }
+
+    /// Decrypt the vote histogram from `KnnServer::class_counts`
+    /// and normalize it into class probabilities.
+    pub fn decrypt_histogram(&self, counts: &[Ciphertext]) -> Vec<f64> {
+        let counts: Vec<_> = counts.iter().map(|c| self.key.decrypt(c)).collect();
+        let total: u64 = counts.iter().sum();
+        counts
+            .into_iter()
+            .map(|c| {
+                if total == 0 {
+                    0f64
+                } else {
+                    c as f64 / total as f64
+                }
+            })
+            .collect()
+    }
 }
 
 /// Generate the key switching keys for `lwe_to_glwe`.
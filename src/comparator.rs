@@ -1,12 +1,15 @@
+use crate::server::with_polymul_fft;
 use crate::server::KnnServer;
-use crate::setup_polymul_fft;
 use dyn_stack::DynStack;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::cmp::{Ord, Ordering};
 use std::fmt;
 use std::marker::PhantomData;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex, RwLock};
+use tfhe::core_crypto::fft_impl::math::fft::FftView;
 use tfhe::shortint::prelude::*;
 
 #[derive(Eq, Copy, Clone)]
@@ -90,24 +93,136 @@ impl<T: Ord + Clone> Comparator for ClearComparator<T> {
     }
 }
 
+/// An encrypted item that is sorted by `value`,
+/// the ciphertexts in `payload` move together with the value in the comparators.
+/// The first element of the payload is always the class (or the regression target),
+/// the rest is set per model, e.g., the digits of the record id.
+/// For radix distances, `value` is the most significant block
+/// and the other blocks are in `low_blocks`.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EncItem {
     pub value: Ciphertext,
-    pub class: Ciphertext,
+    pub low_blocks: Vec<Ciphertext>,
+    pub payload: Vec<Ciphertext>,
 }
 
 impl EncItem {
     pub fn new(value: Ciphertext, class: Ciphertext) -> Self {
-        Self { value, class }
+        Self {
+            value,
+            low_blocks: vec![],
+            payload: vec![class],
+        }
+    }
+
+    /// Create an item with an arbitrary non-empty payload.
+    pub fn new_with_payload(value: Ciphertext, payload: Vec<Ciphertext>) -> Self {
+        assert!(!payload.is_empty());
+        Self {
+            value,
+            low_blocks: vec![],
+            payload,
+        }
+    }
+
+    /// Create an item where the value is split into radix blocks,
+    /// the most significant block first.
+    pub fn new_radix(mut blocks: Vec<Ciphertext>, payload: Vec<Ciphertext>) -> Self {
+        assert!(!blocks.is_empty());
+        assert!(!payload.is_empty());
+        let low_blocks = blocks.split_off(1);
+        Self {
+            value: blocks.pop().unwrap(),
+            low_blocks,
+            payload,
+        }
+    }
+
+    /// All the blocks of the value, the most significant first.
+    pub fn value_blocks(&self) -> Vec<&Ciphertext> {
+        std::iter::once(&self.value)
+            .chain(self.low_blocks.iter())
+            .collect()
+    }
+
+    /// The class is the first element of the payload.
+    pub fn class(&self) -> &Ciphertext {
+        &self.payload[0]
     }
 
     pub fn decrypt(&self, client_key: &ClientKey) -> (u64, u64) {
         (
             client_key.decrypt(&self.value),
-            client_key.decrypt(&self.class),
+            client_key.decrypt(self.class()),
+        )
+    }
+}
+
+/// Compute the item with the smaller value homomorphically using an existing FFT context,
+/// every payload ciphertext follows the value using `arg_min`,
+/// so the cost is one bootstrap per payload ciphertext plus one for the value.
+/// Radix values are compared lexicographically first,
+/// then every block and payload ciphertext is selected with one bootstrap.
+fn enc_min_item(
+    server: &KnnServer,
+    a: &EncItem,
+    b: &EncItem,
+    fft: FftView,
+    stack: &mut DynStack,
+) -> EncItem {
+    assert_eq!(a.payload.len(), b.payload.len());
+    assert_eq!(a.low_blocks.len(), b.low_blocks.len());
+    if a.low_blocks.is_empty() {
+        let value = server.min_with_fft(&a.value, &b.value, fft, stack);
+        let payload = a
+            .payload
+            .iter()
+            .zip(b.payload.iter())
+            .map(|(i, j)| server.arg_min_with_fft(&a.value, &b.value, i, j, fft, stack))
+            .collect();
+        EncItem::new_with_payload(value, payload)
+    } else {
+        let selector = server.lexicographic_selector(&a.value_blocks(), &b.value_blocks());
+        let mut select = |(i, j): (&Ciphertext, &Ciphertext)| {
+            server.select_with_fft(&selector, i, j, fft, stack)
+        };
+        EncItem::new_radix(
+            a.value_blocks()
+                .into_iter()
+                .zip(b.value_blocks())
+                .map(&mut select)
+                .collect(),
+            a.payload
+                .iter()
+                .zip(b.payload.iter())
+                .map(&mut select)
+                .collect(),
         )
     }
 }
 
+/// Compute the item with the larger value from the smaller one,
+/// every ciphertext is `a + b - min` so no bootstrapping is needed.
+fn enc_max_item(server: &KnnServer, a: &EncItem, b: &EncItem, min: &EncItem) -> EncItem {
+    let max = |x: &Ciphertext, y: &Ciphertext, m: &Ciphertext| {
+        let mut out = server.raw_add(x, y);
+        server.raw_sub_assign(&mut out, m);
+        out
+    };
+    let max_all = |xs: &[Ciphertext], ys: &[Ciphertext], ms: &[Ciphertext]| -> Vec<_> {
+        xs.iter()
+            .zip(ys.iter())
+            .zip(ms.iter())
+            .map(|((i, j), m)| max(i, j, m))
+            .collect()
+    };
+    EncItem {
+        value: max(&a.value, &b.value, &min.value),
+        low_blocks: max_all(&a.low_blocks, &b.low_blocks, &min.low_blocks),
+        payload: max_all(&a.payload, &b.payload, &min.payload),
+    }
+}
+
 pub struct EncComparator {
     params: Parameters,
     server: Rc<RefCell<KnnServer>>,
@@ -132,35 +247,14 @@ impl Comparator for EncComparator {
     type Aux = ();
 
     fn compare(&self, vs: &mut [Self::Item], i: usize, j: usize) {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let fft = fft.as_view();
-        let mut stack = DynStack::new(&mut mem);
-
-        let min_value =
-            self.server
-                .borrow()
-                .min_with_fft(&vs[i].value, &vs[j].value, fft, &mut stack);
-        let min_class = self.server.borrow().arg_min_with_fft(
-            &vs[i].value,
-            &vs[j].value,
-            &vs[i].class,
-            &vs[j].class,
-            fft,
-            &mut stack,
-        );
-
-        let mut max_value = self.server.borrow().raw_add(&vs[i].value, &vs[j].value);
-        self.server
-            .borrow()
-            .raw_sub_assign(&mut max_value, &min_value);
-
-        let mut max_class = self.server.borrow().raw_add(&vs[i].class, &vs[j].class);
-        self.server
-            .borrow()
-            .raw_sub_assign(&mut max_class, &min_class);
-
-        vs[i] = EncItem::new(min_value, min_class);
-        vs[j] = EncItem::new(max_value, max_class);
+        let server = self.server.borrow();
+        let min = with_polymul_fft(self.params, |fft, stack| {
+            enc_min_item(&server, &vs[i], &vs[j], fft, stack)
+        });
+        let max = enc_max_item(&server, &vs[i], &vs[j], &min);
+
+        vs[i] = min;
+        vs[j] = max;
         *self.counter.borrow_mut() += 1;
     }
 
@@ -181,6 +275,13 @@ pub trait AsyncComparator: Sync + Send {
     fn compare(&self, a: &Self::Item, b: &Self::Item);
     fn swap(&self, a: &Self::Item, b: &Self::Item);
     fn compare_count(&self) -> usize;
+
+    /// Compare every pair in `pairs`, the pairs must be independent,
+    /// e.g., they are on the same level of a sorting network.
+    /// By default every comparison is dispatched on its own rayon task.
+    fn compare_batch(&self, pairs: &[(&Self::Item, &Self::Item)]) {
+        pairs.par_iter().for_each(|(a, b)| self.compare(a, b));
+    }
 }
 
 #[derive(Clone)]
@@ -276,10 +377,6 @@ impl AsyncComparator for AsyncEncComparator {
     type Aux = ();
 
     fn compare(&self, a: &Self::Item, b: &Self::Item) {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let fft = fft.as_view();
-        let mut stack = DynStack::new(&mut mem);
-
         let a = a.clone();
         let b = b.clone();
         let mut a_guard = a.lock().unwrap();
@@ -287,24 +384,13 @@ impl AsyncComparator for AsyncEncComparator {
         let server = self.server.clone();
         let server_guard = server.read().unwrap();
 
-        let min_value = server_guard.min_with_fft(&a_guard.value, &b_guard.value, fft, &mut stack);
-        let min_class = server_guard.arg_min_with_fft(
-            &a_guard.value,
-            &b_guard.value,
-            &a_guard.class,
-            &b_guard.class,
-            fft,
-            &mut stack,
-        );
-
-        let mut max_value = server_guard.raw_add(&a_guard.value, &b_guard.value);
-        server_guard.raw_sub_assign(&mut max_value, &min_value);
+        let min = with_polymul_fft(self.params, |fft, stack| {
+            enc_min_item(&server_guard, &a_guard, &b_guard, fft, stack)
+        });
+        let max = enc_max_item(&server_guard, &a_guard, &b_guard, &min);
 
-        let mut max_class = server_guard.raw_add(&a_guard.class, &b_guard.class);
-        server_guard.raw_sub_assign(&mut max_class, &min_class);
-
-        *a_guard = EncItem::new(min_value, min_class);
-        *b_guard = EncItem::new(max_value, max_class);
+        *a_guard = min;
+        *b_guard = max;
 
         if self.do_count {
             let ctr = self.counter.clone();
@@ -324,4 +410,65 @@ impl AsyncComparator for AsyncEncComparator {
     fn compare_count(&self) -> usize {
         *self.counter.lock().unwrap()
     }
+
+    /// Compare all the pairs with one batch of bootstraps,
+    /// see `KnnServer::batch_keyswitch_programmable_bootstrap`.
+    fn compare_batch(&self, pairs: &[(&Self::Item, &Self::Item)]) {
+        // the lexicographic comparison of radix values needs several rounds of bootstraps,
+        // so those pairs are compared one by one
+        let radix = pairs
+            .first()
+            .is_some_and(|(a, _)| !a.lock().unwrap().low_blocks.is_empty());
+        if radix {
+            pairs.par_iter().for_each(|(a, b)| self.compare(a, b));
+            return;
+        }
+
+        let mut guards: Vec<_> = pairs
+            .iter()
+            .map(|(a, b)| (a.lock().unwrap(), b.lock().unwrap()))
+            .collect();
+        let server = self.server.clone();
+        let server_guard = server.read().unwrap();
+
+        // every comparator needs bootstraps with the same input,
+        // one for the value and one for every payload ciphertext
+        let width = guards
+            .first()
+            .map_or(0, |(a_guard, _)| 1 + a_guard.payload.len());
+        let inputs: Vec<_> = guards
+            .par_iter()
+            .flat_map_iter(|(a_guard, b_guard)| {
+                assert_eq!(a_guard.payload.len() + 1, width);
+                assert_eq!(b_guard.payload.len() + 1, width);
+                with_polymul_fft(self.params, |fft, stack| {
+                    let diff = server_guard.special_sub(&b_guard.value, &a_guard.value);
+                    let mut out = Vec::with_capacity(width);
+                    out.push((
+                        diff.clone(),
+                        server_guard.double_ct_acc(&a_guard.value, &b_guard.value, fft, stack),
+                    ));
+                    for (i, j) in a_guard.payload.iter().zip(b_guard.payload.iter()) {
+                        out.push((diff.clone(), server_guard.double_ct_acc(i, j, fft, stack)));
+                    }
+                    out
+                })
+            })
+            .collect();
+        let outputs = server_guard.batch_keyswitch_programmable_bootstrap(&inputs);
+
+        for ((a_guard, b_guard), mins) in guards.iter_mut().zip(outputs.chunks(width.max(1))) {
+            let min = EncItem::new_with_payload(mins[0].clone(), mins[1..].to_vec());
+            let max = enc_max_item(&server_guard, a_guard, b_guard, &min);
+
+            **a_guard = min;
+            **b_guard = max;
+        }
+
+        if self.do_count {
+            let ctr = self.counter.clone();
+            let mut curr = ctr.lock().unwrap();
+            *curr += pairs.len();
+        }
+    }
 }
@@ -0,0 +1,255 @@
+use std::fmt::{Display, Formatter};
+
+/// The errors returned when a model does not fit the chosen parameters.
+#[derive(Debug)]
+pub enum KnnError {
+    /// The rows have more features than the polynomial size allows.
+    TooManyFeatures {
+        gamma: usize,
+        polynomial_size: usize,
+    },
+    /// A row does not have the same number of features as the model.
+    RowLength {
+        row: usize,
+        len: usize,
+        gamma: usize,
+    },
+    /// The squared distance between a row and a query in the declared query range
+    /// may not be smaller than half of the distance plaintext modulus,
+    /// see `DistanceAnalysis::check`.
+    DistanceOverflow {
+        max_distance: u64,
+        dist_modulus: u64,
+    },
+    /// The evaluation keys do not give a power of two distance modulus
+    /// that is at least the message modulus, see `EvaluationKeys::dist_modulus`.
+    InvalidEvaluationKeys {
+        dist_delta: u64,
+        message_modulus: u64,
+        carry_modulus: u64,
+    },
+    /// The ratio between the distance and the sorting plaintext moduli is not
+    /// `base^(blocks - 1)` for a base between 2 and a quarter of the message modulus,
+    /// see `KnnServer::set_distance_blocks`.
+    DistanceBlocks {
+        blocks: usize,
+        ratio: u64,
+        message_modulus: usize,
+    },
+    /// The labels need at least one block.
+    NoLabelBlocks,
+    /// A label does not fit in the label blocks.
+    LabelTooLarge {
+        row: usize,
+        label: u64,
+        blocks: usize,
+        base: u64,
+    },
+    /// The number of labels is not the same as the number of rows.
+    LabelCount { rows: usize, labels: usize },
+    /// A row index is not smaller than the number of rows.
+    RowOutOfRange { row: usize, n_rows: usize },
+    /// The number of payloads is not the same as the number of rows.
+    PayloadCount { rows: usize, payloads: usize },
+    /// A payload does not have the same length as the first one.
+    PayloadLength {
+        row: usize,
+        len: usize,
+        width: usize,
+    },
+    /// A payload value does not fit in the message modulus.
+    PayloadTooLarge {
+        row: usize,
+        value: u64,
+        message_modulus: usize,
+    },
+    /// Rows cannot be inserted when the model has an extra payload, see `KnnServer::set_payload`.
+    ExtraPayload,
+    /// The rank selection needs the number of rows to fit in the message modulus.
+    RankTooLong {
+        n_rows: usize,
+        message_modulus: usize,
+    },
+    /// The sum of `k` regression targets does not fit in the message modulus.
+    RegressionOverflow {
+        k: usize,
+        max_target: u64,
+        message_modulus: usize,
+    },
+    /// Voting and regression only support labels in one block, see `set_labels_with_blocks`.
+    MultiBlockLabels { label_blocks: usize },
+    /// A class score can reach `max_score`, which is not smaller than `limit`.
+    VoteOverflow { max_score: u64, limit: u64 },
+    /// There are no neighbours to vote or to average.
+    NoNeighbours,
+    /// There are no class scores to pick from.
+    NoClasses,
+    /// `k` is zero or higher than the number of rows.
+    KOutOfRange { k: usize, n_rows: usize },
+    /// None of the parameter presets fits the dataset shape, see `plan`.
+    NoParameters {
+        n_features: usize,
+        n_classes: usize,
+        k: usize,
+    },
+    /// There is no parameter preset with this name, see `PresetRegistry`.
+    UnknownPreset { name: String, known: String },
+    /// A preset file could not be read or written.
+    PresetFile(std::io::Error),
+    /// A model file could not be read.
+    ModelFile(std::io::Error),
+    /// A model file could not be decoded or written.
+    Serialization(bincode::Error),
+}
+
+impl Display for KnnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KnnError::TooManyFeatures {
+                gamma,
+                polynomial_size,
+            } => write!(
+                f,
+                "the rows have {gamma} features but the polynomial size is {polynomial_size}, \
+                at most {} features are supported",
+                polynomial_size - 1
+            ),
+            KnnError::RowLength { row, len, gamma } => write!(
+                f,
+                "row {row} has {len} features but the model has {gamma} features"
+            ),
+            KnnError::DistanceOverflow {
+                max_distance,
+                dist_modulus,
+            } => write!(
+                f,
+                "the squared distance between a row and a query can be up to {max_distance} \
+                but it must be smaller than {}, half of the distance modulus {dist_modulus}, \
+                the query is in the declared query range or, if there is none, \
+                between zero and the highest value of every feature in the model",
+                dist_modulus / 2
+            ),
+            KnnError::InvalidEvaluationKeys {
+                dist_delta,
+                message_modulus,
+                carry_modulus,
+            } => write!(
+                f,
+                "invalid evaluation keys, dist_delta={dist_delta} with message modulus \
+                {message_modulus} and carry modulus {carry_modulus} does not give a power of two \
+                distance modulus that is at least the message modulus"
+            ),
+            KnnError::DistanceBlocks {
+                blocks,
+                ratio,
+                message_modulus,
+            } => write!(
+                f,
+                "the distances cannot be split into {blocks} blocks, the ratio {ratio} \
+                between the distance modulus and the message modulus must be base^(blocks - 1) \
+                for a base between 2 and {}",
+                message_modulus / 4
+            ),
+            KnnError::NoLabelBlocks => write!(f, "the labels need at least one block"),
+            KnnError::LabelTooLarge {
+                row,
+                label,
+                blocks,
+                base,
+            } => write!(
+                f,
+                "the label {label} of row {row} does not fit in {blocks} blocks of base {base}, \
+                the labels must be smaller than {}",
+                base.saturating_pow(*blocks as u32)
+            ),
+            KnnError::LabelCount { rows, labels } => {
+                write!(f, "there are {rows} rows but {labels} labels")
+            }
+            KnnError::RowOutOfRange { row, n_rows } => {
+                write!(f, "row {row} does not exist, the model has {n_rows} rows")
+            }
+            KnnError::PayloadCount { rows, payloads } => {
+                write!(f, "there are {rows} rows but {payloads} payloads")
+            }
+            KnnError::PayloadLength { row, len, width } => write!(
+                f,
+                "the payload of row {row} has {len} values but the first one has {width}"
+            ),
+            KnnError::PayloadTooLarge {
+                row,
+                value,
+                message_modulus,
+            } => write!(
+                f,
+                "the payload value {value} of row {row} does not fit in the message modulus {message_modulus}"
+            ),
+            KnnError::ExtraPayload => {
+                write!(
+                    f,
+                    "rows cannot be inserted when there is an extra payload, remove it first"
+                )
+            }
+            KnnError::RankTooLong {
+                n_rows,
+                message_modulus,
+            } => write!(
+                f,
+                "the rank selection supports at most {message_modulus} rows but there are {n_rows}"
+            ),
+            KnnError::RegressionOverflow {
+                k,
+                max_target,
+                message_modulus,
+            } => write!(
+                f,
+                "the sum of {k} targets up to {max_target} does not fit in the message modulus {message_modulus}"
+            ),
+            KnnError::MultiBlockLabels { label_blocks } => write!(
+                f,
+                "voting and regression need single block labels but the labels have {label_blocks} blocks"
+            ),
+            KnnError::VoteOverflow { max_score, limit } => write!(
+                f,
+                "a class score can reach {max_score} but it must be smaller than {limit}"
+            ),
+            KnnError::NoNeighbours => write!(f, "there are no neighbours"),
+            KnnError::NoClasses => write!(f, "there are no class scores"),
+            KnnError::KOutOfRange { k, n_rows } => write!(
+                f,
+                "k = {k} must be between 1 and the number of rows, which is {n_rows}"
+            ),
+            KnnError::NoParameters {
+                n_features,
+                n_classes,
+                k,
+            } => write!(
+                f,
+                "no parameter preset supports {n_features} features, {n_classes} classes \
+                and k = {k} with the required failure probability"
+            ),
+            KnnError::UnknownPreset { name, known } => {
+                write!(
+                    f,
+                    "unknown parameter preset {name}, the presets are: {known}"
+                )
+            }
+            KnnError::PresetFile(e) => write!(f, "invalid preset file: {e}"),
+            KnnError::ModelFile(e) => write!(f, "cannot read the model file: {e}"),
+            KnnError::Serialization(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for KnnError {}
+
+impl From<bincode::Error> for KnnError {
+    fn from(value: bincode::Error) -> Self {
+        KnnError::Serialization(value)
+    }
+}
+
+impl From<KnnError> for std::io::Error {
+    fn from(value: KnnError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, value)
+    }
+}
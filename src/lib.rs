@@ -1,11 +1,27 @@
+pub mod analysis;
 pub mod batcher;
 pub mod clear_knn;
 pub mod client;
 pub mod comparator;
+pub mod error;
+pub mod message;
+pub mod model;
 pub mod network;
+pub mod params;
+pub mod planner;
+pub mod protocol;
+pub mod rank;
+pub mod registry;
 pub mod server;
 
+pub use analysis::*;
 pub use batcher::*;
 pub use client::*;
 pub use comparator::*;
+pub use error::*;
+pub use model::*;
+pub use params::*;
+pub use planner::*;
+pub use rank::*;
+pub use registry::*;
 pub use server::*;
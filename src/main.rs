@@ -1,11 +1,15 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use ppknn::message::*;
 use ppknn::network::*;
+use ppknn::protocol;
 use ppknn::*;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs;
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 use tfhe::shortint::prelude::*;
 
 const MAX_MODEL: u64 = 16;
@@ -33,10 +37,21 @@ impl Debug for QuantizeType {
     }
 }
 
+impl From<QuantizeType> for Quantization {
+    fn from(value: QuantizeType) -> Self {
+        match value {
+            QuantizeType::None => Quantization::None,
+            QuantizeType::Binary => Quantization::Binary,
+            QuantizeType::Ternary => Quantization::Ternary,
+        }
+    }
+}
+
 #[derive(ValueEnum, Clone, Copy)]
 enum NetworkType {
     Normal,
     File,
+    Rank,
 }
 
 impl Display for NetworkType {
@@ -44,6 +59,7 @@ impl Display for NetworkType {
         match self {
             NetworkType::Normal => write!(f, "normal"),
             NetworkType::File => write!(f, "file"),
+            NetworkType::Rank => write!(f, "rank"),
         }
     }
 }
@@ -54,9 +70,273 @@ impl Debug for NetworkType {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy)]
+enum DistanceType {
+    Plain,
+    Packed,
+}
+
+impl Display for DistanceType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistanceType::Plain => write!(f, "plain"),
+            DistanceType::Packed => write!(f, "packed"),
+        }
+    }
+}
+
+impl Debug for DistanceType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl From<DistanceType> for DistanceMode {
+    fn from(value: DistanceType) -> Self {
+        match value {
+            DistanceType::Plain => DistanceMode::Plain,
+            DistanceType::Packed => DistanceMode::Packed,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum WeightType {
+    Uniform,
+    Inverse,
+    Step,
+}
+
+impl Display for WeightType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WeightType::Uniform => write!(f, "uniform"),
+            WeightType::Inverse => write!(f, "inverse"),
+            WeightType::Step => write!(f, "step"),
+        }
+    }
+}
+
+impl Debug for WeightType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum RegressionType {
+    None,
+    ExactMean,
+    RoundedMean,
+}
+
+impl Display for RegressionType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegressionType::None => write!(f, "none"),
+            RegressionType::ExactMean => write!(f, "exact-mean"),
+            RegressionType::RoundedMean => write!(f, "rounded-mean"),
+        }
+    }
+}
+
+impl Debug for RegressionType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Generate the client key and the evaluation keys for the server
+    Keygen {
+        #[clap(
+            long,
+            default_value = "client.key",
+            help = "output path of the client key"
+        )]
+        client_key: PathBuf,
+
+        #[clap(
+            long,
+            default_value = "eval.key",
+            help = "output path of the evaluation keys"
+        )]
+        eval_keys: PathBuf,
+
+        #[clap(
+            long = "model",
+            default_value = "model.bin",
+            help = "a model that is served with these keys, can be repeated"
+        )]
+        models: Vec<PathBuf>,
+
+        #[clap(long, help = "highest value of the query features")]
+        query_max: Option<u64>,
+
+        #[clap(
+            long,
+            default_value_t = 0,
+            help = "compute the distance with higher message modulus, \
+            picked from the models when zero"
+        )]
+        initial_modulus: u64,
+    },
+    /// Convert the first rows of a csv file into a model file
+    Model {
+        #[clap(
+            long,
+            help = "path to the csv file containing the model, the last column is the label"
+        )]
+        file_name: String,
+
+        #[clap(long, default_value_t = 100, help = "size of the model")]
+        model_size: usize,
+
+        #[clap(long, default_value_t = QuantizeType::None)]
+        quantize_type: QuantizeType,
+
+        #[clap(long, default_value = "model.bin", help = "output path of the model")]
+        output: PathBuf,
+    },
+    /// Serve models to many clients over TCP, every client sends its evaluation keys first
+    Serve {
+        #[clap(
+            long = "model",
+            default_value = "default=model.bin",
+            help = "a model to serve as name=path, can be repeated, \
+            the model is reloaded when the file changes"
+        )]
+        models: Vec<String>,
+
+        #[arg(
+            short,
+            default_value_t = 3,
+            help = "k in knn when the query does not set it"
+        )]
+        k: usize,
+
+        #[clap(
+            long,
+            default_value_t = 2,
+            help = "seconds between the checks for changed model files"
+        )]
+        reload_interval: u64,
+
+        #[clap(
+            long,
+            help = "highest value of the query features, the model is rejected if the distances can wrap around"
+        )]
+        query_max: Option<u64>,
+
+        #[clap(long, default_value_t = DistanceType::Plain)]
+        distance_type: DistanceType,
+
+        #[clap(
+            long,
+            default_value_t = 1,
+            help = "keep every distance as this many radix blocks, needs a higher initial modulus"
+        )]
+        distance_blocks: usize,
+
+        #[clap(long, default_value = "127.0.0.1:7878")]
+        addr: String,
+    },
+    /// Encrypt a feature vector, send it to the server and decrypt the result
+    Query {
+        #[clap(long, default_value = "client.key", help = "path to the client key")]
+        client_key: PathBuf,
+
+        #[clap(long, default_value = "eval.key", help = "path to the evaluation keys")]
+        eval_keys: PathBuf,
+
+        #[clap(
+            long,
+            default_value = "default",
+            help = "name of the model on the server"
+        )]
+        model: String,
+
+        #[clap(long, default_value = "127.0.0.1:7878")]
+        addr: String,
+
+        #[arg(
+            short,
+            default_value_t = 0,
+            help = "k in knn, 0 uses the default of the model"
+        )]
+        k: usize,
+
+        #[clap(long, help = "comma separated feature vector")]
+        target: String,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "only receive the majority class instead of the k nearest items"
+        )]
+        majority: bool,
+    },
+    /// Pick the parameters for a dataset shape and predict the cost of one query
+    Plan {
+        #[clap(long, help = "number of features")]
+        n_features: usize,
+
+        #[clap(long, default_value_t = 0, help = "smallest value of a feature")]
+        min_value: u64,
+
+        #[clap(
+            long,
+            help = "highest value of a feature, in the model and in the query"
+        )]
+        max_value: u64,
+
+        #[clap(long, default_value_t = 2, help = "number of classes")]
+        n_classes: usize,
+
+        #[clap(long, default_value_t = 100, help = "size of the model")]
+        model_size: usize,
+
+        #[arg(short, default_value_t = 3, help = "k in knn")]
+        k: usize,
+
+        #[clap(
+            long,
+            default_value_t = 1e-6,
+            help = "highest acceptable probability that a query fails"
+        )]
+        max_failure: f64,
+
+        #[clap(
+            long,
+            default_value_t = false,
+            help = "generate keys and time one bootstrap to predict the runtime"
+        )]
+        measure: bool,
+    },
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about="Privacy preserving k nearest neighbour", long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[clap(
+        long,
+        global = true,
+        default_value = DEFAULT_PRESET,
+        help = "name of the parameter preset, e.g., msg16, msg32, msg64 or msg128"
+    )]
+    params: String,
+
+    #[clap(
+        long,
+        global = true,
+        help = "JSON file with custom parameter presets, they replace the built-in ones with the same name"
+    )]
+    params_file: Option<PathBuf>,
+
     #[clap(
         long,
         default_value = "",
@@ -76,16 +356,75 @@ struct Cli {
     #[clap(
         long,
         default_value_t = 0,
-        help = "compute the distance with higher message modulus"
+        help = "compute the distance with higher message modulus, picked by the overflow analysis if 0"
     )]
     initial_modulus: u64,
 
+    #[clap(
+        long,
+        help = "highest value of the query features, by default the query is assumed to be in the range of the model"
+    )]
+    query_max: Option<u64>,
+
     #[clap(long, default_value_t = QuantizeType::None)]
     quantize_type: QuantizeType,
 
     #[clap(long, default_value_t = NetworkType::Normal)]
     network_type: NetworkType,
 
+    #[clap(long, default_value_t = DistanceType::Plain)]
+    distance_type: DistanceType,
+
+    #[clap(
+        long,
+        default_value_t = 1,
+        help = "keep every distance as this many radix blocks, needs a higher initial modulus"
+    )]
+    distance_blocks: usize,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "compute the majority class on the server and only decrypt the predicted class"
+    )]
+    server_majority: bool,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "compute the vote histogram on the server and only decrypt the class probabilities"
+    )]
+    server_histogram: bool,
+
+    #[clap(
+        long,
+        default_value_t = WeightType::Uniform,
+        help = "weight of every neighbour in the vote, only used with --server-majority"
+    )]
+    weight_type: WeightType,
+
+    #[clap(
+        long,
+        default_value_t = 2,
+        help = "distances below this threshold have the highest weight when using the step weight"
+    )]
+    step_threshold: u64,
+
+    #[clap(
+        long,
+        default_value_t = RegressionType::None,
+        help = "treat the labels as targets and compute their mean, exact-mean decrypts the sum \
+        and rounded-mean rounds on the server, k times the highest target must fit in the message modulus"
+    )]
+    regression_type: RegressionType,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "attach encrypted record ids to the distances and decrypt the ids of the k nearest rows"
+    )]
+    search: bool,
+
     #[clap(long, default_value_t = false, help = "attempt to find the best model")]
     best_model: bool,
 
@@ -159,112 +498,452 @@ fn parse_csv(f_handle: fs::File, quantize_type: QuantizeType) -> Vec<Vec<u64>> {
     rows
 }
 
-const PARAMS: Parameters = Parameters {
-    message_modulus: MessageModulus(32),
-    carry_modulus: CarryModulus(1),
-    ..PARAM_MESSAGE_2_CARRY_3
-};
+/// Pick the smallest modulus where every distance of all the models is smaller than half of it
+/// when `initial_modulus` is zero, otherwise check that `initial_modulus` is large enough.
+/// The comparisons compute `a - b + t/2`, so a distance in the upper half compares wrongly.
+fn dist_modulus(
+    params: Parameters,
+    models: &[&[Vec<u64>]],
+    query_range: Option<ValueRange>,
+    initial_modulus: u64,
+) -> u64 {
+    let analyses: Vec<_> = models
+        .iter()
+        .map(|rows| match query_range {
+            Some(range) => DistanceAnalysis::new(rows, range),
+            None => DistanceAnalysis::in_model_range(rows),
+        })
+        .collect();
+    if initial_modulus == 0 {
+        analyses
+            .iter()
+            .map(|a| a.pick_dist_modulus(params))
+            .max()
+            .unwrap_or(params.message_modulus.0 as u64)
+    } else {
+        for a in &analyses {
+            a.check(initial_modulus).unwrap_or_else(|e| panic!("{e}"));
+        }
+        initial_modulus
+    }
+}
 
 fn setup_simulation(
     params: Parameters,
     model_vec: &[Vec<u64>],
     labels: &[u64],
     initial_modulus: u64,
+    query_range: Option<ValueRange>,
+    distance_type: DistanceType,
+    distance_blocks: usize,
 ) -> (KnnClient, Arc<RwLock<KnnServer>>) {
-    let (client, server) = setup_with_data(
-        params,
-        model_vec,
-        labels,
-        if initial_modulus == 0 {
-            params.message_modulus.0 as u64
-        } else {
-            initial_modulus
-        },
-    );
+    let initial_modulus = dist_modulus(params, &[model_vec], query_range, initial_modulus);
+    let (client, mut server) = setup_with_modulus(params, initial_modulus);
+    server.set_query_range(query_range);
+    server.set_data(model_vec).unwrap_or_else(|e| panic!("{e}"));
+    server.set_labels(labels).unwrap_or_else(|e| panic!("{e}"));
+    server.set_distance_mode(distance_type.into());
+    server
+        .set_distance_blocks(distance_blocks)
+        .unwrap_or_else(|e| panic!("{e}"));
     let server = Arc::new(RwLock::new(server));
     (client, server)
 }
 
+/// The output of one simulated query, only the decrypted values are kept.
+struct SimulationOutput {
+    decrypted_k: Vec<(u64, u64)>,
+    dist_dur: u128,
+    server_dur: u128,
+    comparisons: usize,
+    first_noise: f64,
+    server_maj: Option<u64>,
+    server_hist: Option<Vec<f64>>,
+    server_mean: Option<f64>,
+    server_ids: Option<Vec<u64>>,
+}
+
+impl Cli {
+    fn regression_output(&self) -> Option<RegressionOutput> {
+        match self.regression_type {
+            RegressionType::None => None,
+            RegressionType::ExactMean => Some(RegressionOutput::ExactMean),
+            RegressionType::RoundedMean => Some(RegressionOutput::RoundedMean),
+        }
+    }
+
+    fn vote_weight(&self) -> VoteWeight {
+        match self.weight_type {
+            WeightType::Uniform => VoteWeight::Uniform,
+            WeightType::Inverse => VoteWeight::InverseDistance,
+            WeightType::Step => VoteWeight::Step(self.step_threshold),
+        }
+    }
+}
+
 fn simulate(
     params: Parameters,
     client: &mut KnnClient,
     server: Arc<RwLock<KnnServer>>,
-    k: usize,
     target: &[u64],
-    verbose: bool,
-    network_type: NetworkType,
-) -> (Vec<(u64, u64)>, u128, u128, usize, f64) {
+    cli: &Cli,
+) -> SimulationOutput {
+    let k = cli.k;
+    let label_blocks = server.read().unwrap().label_blocks();
     let (glwe, lwe) = client.make_query(target);
 
     let server_start = Instant::now();
-    let distances_labels: Vec<Arc<Mutex<_>>> = server
-        .read()
-        .unwrap()
-        .compute_distances_with_labels(&glwe, &lwe)
-        .into_iter()
-        .map(|l| Arc::new(Mutex::new(l)))
-        .collect();
+    let items = if cli.search {
+        server
+            .read()
+            .unwrap()
+            .compute_distances_with_ids(&glwe, &lwe)
+    } else {
+        server
+            .read()
+            .unwrap()
+            .compute_distances_with_labels(&glwe, &lwe)
+    };
+    let distances_labels: Vec<Arc<Mutex<_>>> =
+        items.into_iter().map(|l| Arc::new(Mutex::new(l))).collect();
+    // measure the distance computation on its own,
+    // so that it does not include the debug output or loading the network
+    let dist_dur = server_start.elapsed().as_millis();
 
-    if verbose {
+    if cli.verbose {
         let distances: Vec<_> = distances_labels
             .iter()
             .take(10)
             .map(|item| {
                 let value = client.key.decrypt(&item.lock().unwrap().value);
-                let class = client.key.decrypt(&item.lock().unwrap().class);
+                let class = client.decrypt_blocks(&item.lock().unwrap().payload[..label_blocks]);
                 (value, class)
             })
             .collect();
         println!("[DEBUG] decrypted_distances_top10={distances:?}");
     }
 
-    let (dist_dur, server_dur, comparisons) = match network_type {
+    let (server_dur, comparisons) = match cli.network_type {
         NetworkType::Normal => {
             let cmp = AsyncEncComparator::new_with_counter(server.clone(), params);
             let sorter = BatcherSort::par_new_k(k, cmp, false);
-            let dist_dur = server_start.elapsed().as_millis();
             sorter.par_sort(&distances_labels);
             let server_dur = server_start.elapsed().as_millis();
-            (dist_dur, server_dur, sorter.par_comparisons())
+            (server_dur, sorter.par_comparisons())
         }
         NetworkType::File => {
             let mut d: PathBuf = [env!("CARGO_MANIFEST_DIR"), "data"].iter().collect();
             d.push(format!("network-{}-{}.csv", distances_labels.len(), k));
             // TODO load the network early
             let network = load_network(&d).unwrap();
-            let cmp = AsyncEncComparator::new(server, params);
-
-            let dist_dur = server_start.elapsed().as_millis();
+            let cmp = AsyncEncComparator::new(server.clone(), params);
             par_run_network_trivial(&network, cmp, &distances_labels);
 
             let server_dur = server_start.elapsed().as_millis();
-            (dist_dur, server_dur, network.len())
+            (server_dur, network.len())
+        }
+        NetworkType::Rank => {
+            let selector = RankSelect::par_new_k(k, server.clone(), params);
+            selector.par_select(&distances_labels);
+            let server_dur = server_start.elapsed().as_millis();
+            (server_dur, selector.par_comparisons())
         }
     };
 
     let decrypted_k: Vec<_> = distances_labels[..k]
         .iter()
-        .map(|ct| ct.lock().unwrap().decrypt(&client.key))
+        .map(|ct| {
+            let item = ct.lock().unwrap();
+            (
+                client.key.decrypt(&item.value),
+                client.decrypt_blocks(&item.payload[..label_blocks]),
+            )
+        })
         .collect();
 
     let first_noise =
         client.lwe_noise(&distances_labels[0].lock().unwrap().value, decrypted_k[0].0);
-    (decrypted_k, dist_dur, server_dur, comparisons, first_noise)
+
+    // the querier only learns the predicted class in this case
+    let server_maj = if cli.server_majority {
+        let labels: Vec<_> = distances_labels[..k]
+            .iter()
+            .map(|ct| ct.lock().unwrap().class().clone())
+            .collect();
+        let maj = match cli.vote_weight() {
+            VoteWeight::Uniform => server.read().unwrap().majority(&labels),
+            weight => {
+                let distances: Vec<_> = distances_labels[..k]
+                    .iter()
+                    .map(|ct| ct.lock().unwrap().value.clone())
+                    .collect();
+                server
+                    .read()
+                    .unwrap()
+                    .weighted_majority(&distances, &labels, weight)
+            }
+        }
+        .unwrap_or_else(|e| panic!("{e}"));
+        Some(client.key.decrypt(&maj))
+    } else {
+        None
+    };
+
+    // the querier learns the vote histogram but not the individual labels
+    let server_hist = if cli.server_histogram {
+        let labels: Vec<_> = distances_labels[..k]
+            .iter()
+            .map(|ct| ct.lock().unwrap().class().clone())
+            .collect();
+        let counts = server
+            .read()
+            .unwrap()
+            .class_counts(&labels)
+            .unwrap_or_else(|e| panic!("{e}"));
+        Some(client.decrypt_histogram(&counts))
+    } else {
+        None
+    };
+
+    // only the mean of the k targets is decrypted
+    let server_mean = cli.regression_output().map(|output| {
+        let targets: Vec<_> = distances_labels[..k]
+            .iter()
+            .map(|ct| ct.lock().unwrap().class().clone())
+            .collect();
+        let ct = server
+            .read()
+            .unwrap()
+            .regression(&targets, output)
+            .unwrap_or_else(|e| panic!("{e}"));
+        client.decode_regression(&ct, k, output)
+    });
+
+    // the record id comes after the label and the extra payload
+    let server_ids = if cli.search {
+        let id_start = server.read().unwrap().payload_len();
+        Some(
+            distances_labels[..k]
+                .iter()
+                .map(|ct| client.decrypt_id(&ct.lock().unwrap().payload[id_start..]))
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    SimulationOutput {
+        decrypted_k,
+        dist_dur,
+        server_dur,
+        comparisons,
+        first_noise,
+        server_maj,
+        server_hist,
+        server_mean,
+        server_ids,
+    }
+}
+
+fn run_command(presets: &PresetRegistry, params: Parameters, command: Command) {
+    match command {
+        Command::Keygen {
+            client_key,
+            eval_keys,
+            models,
+            query_max,
+            initial_modulus,
+        } => {
+            let models: Vec<_> = models
+                .iter()
+                .map(|path| {
+                    Model::load(path)
+                        .unwrap_or_else(|e| panic!("failed to load {}: {e}", path.display()))
+                        .rows
+                })
+                .collect();
+            let rows: Vec<_> = models.iter().map(|m| m.as_slice()).collect();
+            let initial_modulus = dist_modulus(
+                params,
+                &rows,
+                query_max.map(|max| ValueRange::new(0, max)),
+                initial_modulus,
+            );
+            let (client, keys) = KnnClient::keygen(params, initial_modulus);
+            ClientKeyBundle::new(&client)
+                .save(&client_key)
+                .expect("failed to save the client key");
+            keys.save(&eval_keys)
+                .expect("failed to save the evaluation keys");
+        }
+        Command::Model {
+            file_name,
+            model_size,
+            quantize_type,
+            output,
+        } => {
+            let f_handle = fs::File::open(file_name).expect("csv file not found");
+            let rows = parse_csv(f_handle, quantize_type);
+            let (model_vec, model_labels, _, _) = clear_knn::split_model_test(model_size, 0, rows);
+            Model::new(model_vec, model_labels, quantize_type.into())
+                .save(&output)
+                .expect("failed to save the model");
+        }
+        Command::Serve {
+            models,
+            k,
+            reload_interval,
+            query_max,
+            distance_type,
+            distance_blocks,
+            addr,
+        } => {
+            let files = models
+                .iter()
+                .map(|m| {
+                    let (name, path) = m.split_once('=').expect("the model must be name=path");
+                    (name.to_string(), PathBuf::from(path))
+                })
+                .collect();
+            // the models are only checked against the keys of a client when it queries them
+            let mut watcher = ModelWatcher::new(files, move |model| ModelConfig {
+                distance_mode: distance_type.into(),
+                distance_blocks,
+                query_range: query_max.map(|max| ValueRange::new(0, max)),
+                ..ModelConfig::from_model(model, k)
+            });
+            let registry = Arc::new(Registry::new());
+            for (name, result) in watcher.poll(&registry) {
+                result.unwrap_or_else(|e| panic!("failed to load the model {name}: {e}"));
+            }
+
+            let watched = registry.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(reload_interval));
+                for (name, result) in watcher.poll(&watched) {
+                    match result {
+                        Ok(()) => println!("reloaded the model {name}"),
+                        Err(e) => eprintln!("[ERROR] failed to reload the model {name}: {e}"),
+                    }
+                }
+            });
+
+            let listener = TcpListener::bind(&addr).expect("failed to bind");
+            println!("listening on {addr}");
+            protocol::serve_registry(listener, registry).unwrap();
+        }
+        Command::Query {
+            client_key,
+            eval_keys,
+            model,
+            addr,
+            k,
+            target,
+            majority,
+        } => {
+            let mut client = ClientKeyBundle::load(&client_key)
+                .expect("failed to load the client key")
+                .into_client();
+            let keys =
+                EvaluationKeys::load(&eval_keys).expect("failed to load the evaluation keys");
+            let target: Vec<u64> = target
+                .split(',')
+                .map(|x| x.trim().parse().expect("invalid feature"))
+                .collect();
+            let output = if majority {
+                QueryOutput::Majority
+            } else {
+                QueryOutput::Nearest
+            };
+            let stream = protocol::open_session(addr, &keys).expect("failed to connect");
+            match protocol::send_query_on(&stream, &mut client, &model, &target, k, output).unwrap()
+            {
+                QueryResult::Nearest {
+                    items,
+                    label_blocks,
+                } => {
+                    let nearest: Vec<_> = items
+                        .iter()
+                        .map(|item| {
+                            (
+                                client.key.decrypt(&item.value),
+                                client.decrypt_blocks(&item.payload[..label_blocks]),
+                            )
+                        })
+                        .collect();
+                    println!("nearest={nearest:?}");
+                }
+                QueryResult::Majority(ct) => println!("class={}", client.key.decrypt(&ct)),
+                QueryResult::Error(e) => panic!("the server rejected the query: {e}"),
+            }
+        }
+        Command::Plan {
+            n_features,
+            min_value,
+            max_value,
+            n_classes,
+            model_size,
+            k,
+            max_failure,
+            measure,
+        } => {
+            let shape = DatasetShape {
+                n_features,
+                value_range: ValueRange::new(min_value, max_value),
+                n_classes,
+                model_size,
+                k,
+            };
+            let plan = plan(presets, &shape, max_failure).unwrap_or_else(|e| panic!("{e}"));
+            println!(
+                "params={}, message_modulus={}, network={:?}, max_distance={}, initial_modulus={}, \
+                bootstraps={}, pbs_failure=2^{:.1}, failure_probability={:e}",
+                plan.name,
+                plan.params.message_modulus.0,
+                plan.network,
+                plan.max_distance,
+                plan.dist_modulus,
+                plan.bootstraps.total(),
+                plan.log2_pbs_failure,
+                plan.failure_probability
+            );
+            if measure {
+                let threads = rayon::current_num_threads();
+                let pbs_time = measure_pbs(plan.params, 10);
+                println!(
+                    "pbs_dur={}ms, threads={threads}, predicted_dur={}ms",
+                    pbs_time.as_millis(),
+                    plan.predict_runtime(pbs_time, threads).as_millis()
+                );
+            }
+        }
+    }
 }
 
 fn main() {
-    let params = PARAMS;
     let cli = Cli::parse();
+    let mut presets = PresetRegistry::builtin();
+    if let Some(path) = &cli.params_file {
+        presets.load_file(path).unwrap_or_else(|e| panic!("{e}"));
+    }
+    let params = presets
+        .params(&cli.params)
+        .unwrap_or_else(|e| panic!("{e}"));
+
+    if let Some(command) = cli.command.clone() {
+        run_command(&presets, params, command);
+        return;
+    }
 
     if cli.print_header {
         println!(
             "rep,k,model_size,test_size,quantize_type,dist_dur,total_dur,comparisons,noise,\
-                    actual_maj,clear_maj,expected,clear_ok,enc_ok,threads"
+                    actual_maj,clear_maj,expected,clear_ok,enc_ok,threads,server_maj,server_ok,server_hist,hist_ok,weight_type,clear_wmaj,server_mean,clear_mean,server_ids,ids_ok,params"
         );
         return;
     }
 
-    let csv_file_name = cli.file_name;
+    let csv_file_name = cli.file_name.clone();
     if csv_file_name.is_empty() {
         unimplemented!("reading from stdin not implemented");
     }
@@ -272,8 +951,16 @@ fn main() {
     let f_handle = fs::File::open(csv_file_name.clone()).expect("csv file not found");
     let all_rows = parse_csv(f_handle, cli.quantize_type);
 
+    if matches!(cli.network_type, NetworkType::Rank) {
+        SelectionNetwork::Rank
+            .check(cli.model_size, params)
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
     let mut actual_errs = 0usize;
     let mut clear_errs = 0usize;
+    let mut server_errs = 0usize;
+    let mut clear_weighted_errs = 0usize;
 
     for rep in 0..cli.repetitions {
         let (model_vec, model_labels, test_vec, test_labels) = {
@@ -298,8 +985,23 @@ fn main() {
         assert_eq!(model_labels.len(), cli.model_size);
         assert_eq!(test_labels.len(), cli.test_size);
 
-        let (mut client, server) =
-            setup_simulation(params, &model_vec, &model_labels, cli.initial_modulus);
+        let (mut client, server) = setup_simulation(
+            params,
+            &model_vec,
+            &model_labels,
+            cli.initial_modulus,
+            cli.query_max.map(|max| ValueRange::new(0, max)),
+            cli.distance_type,
+            cli.distance_blocks,
+        );
+        if cli.verbose {
+            let server = server.read().unwrap();
+            println!(
+                "[DEBUG] max_distance={}, dist_modulus={}",
+                server.analysis(&model_vec).max_distance(),
+                server.dist_modulus()
+            );
+        }
 
         for (i, (target, expected)) in test_vec.into_iter().zip(test_labels).enumerate() {
             if cli.verbose {
@@ -315,15 +1017,17 @@ fn main() {
                         .collect::<Vec<_>>()
                 )
             }
-            let (actual_full, dist_dur, total_dur, comparisons, noise) = simulate(
-                params,
-                &mut client,
-                server.clone(),
-                cli.k,
-                &target,
-                cli.verbose,
-                cli.network_type,
-            );
+            let SimulationOutput {
+                decrypted_k: actual_full,
+                dist_dur,
+                server_dur: total_dur,
+                comparisons,
+                first_noise: noise,
+                server_maj,
+                server_hist,
+                server_mean,
+                server_ids,
+            } = simulate(params, &mut client, server.clone(), &target, &cli);
             let actual_labels: Vec<_> = actual_full.iter().map(|(_, b)| *b).collect();
             let actual_maj = clear_knn::majority(&actual_labels);
             assert_eq!(actual_full.len(), cli.k);
@@ -332,30 +1036,91 @@ fn main() {
                 clear_knn::run_knn(cli.k, &model_vec, &model_labels, &target);
             let clear_labels: Vec<_> = clear_full.iter().map(|l| l.class).collect();
             let clear_maj = clear_knn::majority(&clear_labels);
+            // the weighted reference uses the distances at the sorting precision
+            let ratio = client.delta() / client.dist_delta;
+            let max_weight = server.read().unwrap().max_vote_weight(cli.k);
+            let clear_wmaj = clear_knn::weighted_majority(&clear_full, |d| {
+                cli.vote_weight().weight(d / ratio, max_weight)
+            });
+            // empty when the majority is not computed on the server
+            let (server_maj_str, server_ok_str) = match server_maj {
+                Some(m) => (m.to_string(), ((m == expected) as u8).to_string()),
+                None => (String::new(), String::new()),
+            };
+            // the probabilities are separated by ';' to keep the csv format,
+            // `hist_ok` checks them against the plaintext histogram of the k neighbours
+            let (server_hist_str, hist_ok_str) = match &server_hist {
+                Some(hist) => {
+                    let n_classes = server.read().unwrap().n_classes();
+                    let clear_hist = clear_knn::histogram(&clear_labels, n_classes);
+                    let hist_str = hist
+                        .iter()
+                        .map(|p| format!("{p:.2}"))
+                        .collect::<Vec<_>>()
+                        .join(";");
+                    (hist_str, ((*hist == clear_hist) as u8).to_string())
+                }
+                None => (String::new(), String::new()),
+            };
+            let (server_mean_str, clear_mean_str) = match server_mean {
+                Some(m) => {
+                    let (clear_mean, _) =
+                        clear_knn::run_knn_regression(cli.k, &model_vec, &model_labels, &target);
+                    (format!("{m:.2}"), format!("{clear_mean:.2}"))
+                }
+                None => (String::new(), String::new()),
+            };
+            // the ids may be in a different order if there are ties,
+            // so `ids_ok` compares the distances of the returned rows
+            let (server_ids_str, ids_ok_str) = match &server_ids {
+                Some(ids) => {
+                    let clear_ids = clear_knn::search(cli.k, &model_vec, &target);
+                    let all_distances = clear_knn::distances(&model_vec, &target);
+                    let ids_distances = |ids: &[u64]| {
+                        let mut out: Vec<_> =
+                            ids.iter().map(|id| all_distances[*id as usize]).collect();
+                        out.sort();
+                        out
+                    };
+                    let ids_str = ids
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(";");
+                    let ids_ok = ids_distances(ids) == ids_distances(&clear_ids);
+                    (ids_str, (ids_ok as u8).to_string())
+                }
+                None => (String::new(), String::new()),
+            };
             if cli.csv {
                 println!(
                     "{rep},{},{},{},{},{dist_dur},{total_dur},{comparisons},{noise:.2},\
-                    {actual_maj},{clear_maj},{expected},{},{},{}",
+                    {actual_maj},{clear_maj},{expected},{},{},{},{server_maj_str},{server_ok_str},{server_hist_str},{hist_ok_str},{},{clear_wmaj},{server_mean_str},{clear_mean_str},{server_ids_str},{ids_ok_str},{}",
                     cli.k,
                     cli.model_size,
                     cli.test_size,
                     cli.quantize_type,
                     (clear_maj == expected) as u8,
                     (actual_maj == expected) as u8,
-                    rayon::current_num_threads()
+                    rayon::current_num_threads(),
+                    cli.weight_type,
+                    cli.params
                 );
             } else {
                 println!(
                     "rep={rep}, k={}, model_size={}, test_size={}, quantize_type={}, \
                     dist_dur={dist_dur}ms, total_dur={total_dur}ms, comparisons={comparisons}, noise={noise:.2}, \
-                    actual_maj={actual_maj}, clear_maj={clear_maj}, expected={expected}, clear_ok={}, enc_ok={}, threads={}",
+                    actual_maj={actual_maj}, clear_maj={clear_maj}, expected={expected}, clear_ok={}, enc_ok={}, threads={}, \
+                    server_maj={server_maj_str}, server_ok={server_ok_str}, server_hist={server_hist_str}, hist_ok={hist_ok_str}, weight_type={}, clear_wmaj={clear_wmaj}, server_mean={server_mean_str}, clear_mean={clear_mean_str}, server_ids={server_ids_str}, ids_ok={ids_ok_str}, params={}",
                     cli.k,
                     cli.model_size,
                     cli.test_size,
                     cli.quantize_type,
                     (clear_maj==expected) as u8,
                     (actual_maj==expected) as u8,
-                    rayon::current_num_threads()
+                    rayon::current_num_threads(),
+                    cli.weight_type,
+                    cli.params
                 );
             }
 
@@ -365,6 +1130,12 @@ fn main() {
             if clear_maj != expected {
                 clear_errs += 1;
             }
+            if clear_wmaj != expected {
+                clear_weighted_errs += 1;
+            }
+            if server_maj.is_some_and(|m| m != expected) {
+                server_errs += 1;
+            }
 
             if cli.verbose {
                 if actual_maj != expected {
@@ -388,13 +1159,19 @@ fn main() {
         test_size={}, \
         actual_errs={actual_errs}, \
         clear_errs={clear_errs}, \
+        server_errs={server_errs}, \
+        clear_weighted_errs={clear_weighted_errs}, \
         actual_accuracy={:.2}, \
-        clear_accuracy={:.2}",
+        clear_accuracy={:.2}, \
+        server_accuracy={:.2}, \
+        clear_weighted_accuracy={:.2}",
             cli.k,
             cli.model_size,
             cli.test_size,
             1f64 - (actual_errs as f64 / (cli.repetitions * cli.test_size) as f64),
-            1f64 - (clear_errs as f64 / (cli.repetitions * cli.test_size) as f64)
+            1f64 - (clear_errs as f64 / (cli.repetitions * cli.test_size) as f64),
+            1f64 - (server_errs as f64 / (cli.repetitions * cli.test_size) as f64),
+            1f64 - (clear_weighted_errs as f64 / (cli.repetitions * cli.test_size) as f64)
         );
     }
 }
@@ -0,0 +1,222 @@
+use crate::{EncItem, EvaluationKeys, KnnClient};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use tfhe::core_crypto::prelude::*;
+use tfhe::shortint::prelude::*;
+
+/// The version of the serialization format,
+/// it needs to be increased whenever one of the messages changes.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// The kind of data that follows the header,
+/// so that for example a query cannot be loaded as a key.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    ClientKey,
+    EvaluationKeys,
+    Query,
+    QueryResult,
+    Model,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    kind: MessageKind,
+}
+
+/// Everything that can be written to disk or sent over the network.
+/// Every message is prefixed by a header with the format version and its kind.
+pub trait Message: Serialize + DeserializeOwned {
+    const KIND: MessageKind;
+
+    /// Serialize the message into `writer` using bincode.
+    fn write_to<W: Write>(&self, mut writer: W) -> bincode::Result<()> {
+        let header = Header {
+            version: FORMAT_VERSION,
+            kind: Self::KIND,
+        };
+        bincode::serialize_into(&mut writer, &header)?;
+        bincode::serialize_into(&mut writer, self)
+    }
+
+    /// Deserialize a message from `reader`,
+    /// fails if the version or the kind in the header does not match.
+    fn read_from<R: Read>(mut reader: R) -> bincode::Result<Self> {
+        let header: Header = bincode::deserialize_from(&mut reader)?;
+        if header.version != FORMAT_VERSION {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "unsupported format version {}, expected {FORMAT_VERSION}",
+                header.version
+            ))));
+        }
+        if header.kind != Self::KIND {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "expected {:?} but found {:?}",
+                Self::KIND,
+                header.kind
+            ))));
+        }
+        bincode::deserialize_from(&mut reader)
+    }
+
+    fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
+        let mut out = vec![];
+        self.write_to(&mut out)?;
+        Ok(out)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        Self::read_from(bytes)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> bincode::Result<()> {
+        let f = fs::File::create(path)?;
+        let mut writer = std::io::BufWriter::new(f);
+        self.write_to(&mut writer)?;
+        // dropping the writer would flush it but ignore the error
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(path: P) -> bincode::Result<Self> {
+        let f = fs::File::open(path)?;
+        Self::read_from(std::io::BufReader::new(f))
+    }
+}
+
+/// The secret part of the client,
+/// the encryption RNG is not stored and it is seeded again when loading.
+#[derive(Serialize, Deserialize)]
+pub struct ClientKeyBundle {
+    pub key: ClientKey,
+    pub dist_delta: u64,
+}
+
+impl ClientKeyBundle {
+    pub fn new(client: &KnnClient) -> Self {
+        Self {
+            key: client.key.clone(),
+            dist_delta: client.dist_delta,
+        }
+    }
+
+    pub fn into_client(self) -> KnnClient {
+        KnnClient::from_key(self.key, self.dist_delta)
+    }
+}
+
+/// What the client wants to learn about the `k` nearest neighbours.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryOutput {
+    /// The distances and payloads of the `k` nearest items.
+    Nearest,
+    /// Only the majority class, computed on the server.
+    Majority,
+}
+
+/// The output of `KnnClient::make_query` and the parameters of the query.
+/// The model is only used for routing when the server hosts a `Registry`,
+/// in which case `k = 0` stands for the default `k` of the model.
+#[derive(Serialize, Deserialize)]
+pub struct Query {
+    pub glwe: GlweCiphertextOwned<u64>,
+    pub lwe: Ciphertext,
+    pub k: usize,
+    pub output: QueryOutput,
+    pub model: String,
+}
+
+/// What the server sends back to the client, depending on `Query::output`.
+#[derive(Serialize, Deserialize)]
+pub enum QueryResult {
+    /// The first `label_blocks` payload ciphertexts of every item are the label,
+    /// use `KnnClient::decrypt_blocks` to decrypt it.
+    Nearest {
+        items: Vec<EncItem>,
+        label_blocks: usize,
+    },
+    Majority(Ciphertext),
+    /// The query was rejected, e.g., because `k` is out of range.
+    Error(String),
+}
+
+impl Message for ClientKeyBundle {
+    const KIND: MessageKind = MessageKind::ClientKey;
+}
+
+impl Message for EvaluationKeys {
+    const KIND: MessageKind = MessageKind::EvaluationKeys;
+}
+
+impl Message for Query {
+    const KIND: MessageKind = MessageKind::Query;
+}
+
+impl Message for QueryResult {
+    const KIND: MessageKind = MessageKind::QueryResult;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::TEST_PARAM;
+    use crate::{KnnError, KnnServer};
+
+    #[test]
+    fn test_roundtrip() {
+        let (client, eval_keys) = KnnClient::keygen(TEST_PARAM, 32);
+
+        let bytes = ClientKeyBundle::new(&client).to_bytes().unwrap();
+        let mut loaded = ClientKeyBundle::from_bytes(&bytes).unwrap().into_client();
+        assert_eq!(loaded.dist_delta, client.dist_delta);
+
+        // a key cannot be loaded as something else
+        assert!(Query::from_bytes(&bytes).is_err());
+
+        // the keys that the server receives are checked
+        let bytes = eval_keys.to_bytes().unwrap();
+        for dist_delta in [0, 3, 16, 1 << 62, client.dist_delta * 3] {
+            let mut keys = EvaluationKeys::from_bytes(&bytes).unwrap();
+            keys.dist_delta = dist_delta;
+            assert!(matches!(
+                KnnServer::from_evaluation_keys(keys),
+                Err(KnnError::InvalidEvaluationKeys { .. })
+            ));
+        }
+        let mut server =
+            KnnServer::from_evaluation_keys(EvaluationKeys::from_bytes(&bytes).unwrap()).unwrap();
+        server.set_data(&[vec![0, 1, 0, 0u64]]).unwrap();
+        server.set_labels(&[3]).unwrap();
+
+        // distance should be 2^2 + 1 = 5
+        let (glwe, lwe) = loaded.make_query(&[2, 0, 0, 0]);
+        let bytes = Query {
+            glwe,
+            lwe,
+            k: 1,
+            output: QueryOutput::Nearest,
+            model: String::new(),
+        }
+        .to_bytes()
+        .unwrap();
+        let query = Query::from_bytes(&bytes).unwrap();
+        let items = server.compute_distances_with_labels(&query.glwe, &query.lwe);
+
+        let bytes = QueryResult::Nearest {
+            items,
+            label_blocks: 1,
+        }
+        .to_bytes()
+        .unwrap();
+        match QueryResult::from_bytes(&bytes).unwrap() {
+            QueryResult::Nearest { items, .. } => {
+                assert_eq!(items[0].decrypt(&client.key), (5, 3))
+            }
+            _ => panic!("expected the nearest items"),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::message::{Message, MessageKind};
+use crate::KnnError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// The quantization that was applied to the features before they were put in the model,
+/// queries must be quantized in the same way.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantization {
+    None,
+    Binary,
+    Ternary,
+}
+
+/// The description of one column of the model.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Feature {
+    pub name: String,
+    /// The highest value of this feature in the model.
+    pub max_value: u64,
+}
+
+impl Feature {
+    /// Name the columns `x0`, `x1`, ... and find their highest values in `rows`.
+    pub fn from_rows(rows: &[Vec<u64>]) -> Vec<Feature> {
+        let gamma = rows.iter().fold(0usize, |acc, x| acc.max(x.len()));
+        (0..gamma)
+            .map(|i| Feature {
+                name: format!("x{i}"),
+                max_value: rows
+                    .iter()
+                    .filter_map(|row| row.get(i))
+                    .max()
+                    .copied()
+                    .unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+/// The clear model of a `KnnServer`, it does not depend on any key
+/// so the same file can be loaded by the servers of many clients,
+/// see `KnnServer::save_model` and `KnnServer::load_model`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Model {
+    pub rows: Vec<Vec<u64>>,
+    pub labels: Vec<u64>,
+    /// The length of the longest row.
+    pub gamma: usize,
+    pub quantization: Quantization,
+    pub features: Vec<Feature>,
+}
+
+impl Model {
+    pub fn new(rows: Vec<Vec<u64>>, labels: Vec<u64>, quantization: Quantization) -> Self {
+        assert_eq!(rows.len(), labels.len());
+        let features = Feature::from_rows(&rows);
+        Self {
+            gamma: features.len(),
+            rows,
+            labels,
+            quantization,
+            features,
+        }
+    }
+
+    /// Like `Message::load` but the errors of reading the file
+    /// are kept apart from the errors of decoding it.
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self, KnnError> {
+        let bytes = fs::read(path).map_err(KnnError::ModelFile)?;
+        Ok(Self::from_bytes(&bytes)?)
+    }
+}
+
+impl Message for Model {
+    const KIND: MessageKind = MessageKind::Model;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::TEST_PARAM;
+    use crate::setup;
+
+    #[test]
+    fn test_save_load() {
+        let model = Model::new(
+            vec![vec![0, 1, 0], vec![2, 0, 1], vec![1, 1, 1]],
+            vec![0, 1, 2],
+            Quantization::Ternary,
+        );
+        assert_eq!(model.gamma, 3);
+        assert_eq!(model.features[0].name, "x0");
+        assert_eq!(model.features[0].max_value, 2);
+
+        let (_, mut server) = setup(TEST_PARAM);
+        server.set_model(&model).unwrap();
+        assert_eq!(server.model(), model);
+
+        let path = std::env::temp_dir().join(format!("ppknn-model-{}.bin", std::process::id()));
+        server.save_model(&path).unwrap();
+        server.set_data(&[vec![0, 0]]).unwrap();
+        server.set_labels(&[0]).unwrap();
+        server.load_model(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(server.model(), model);
+        assert_eq!(server.n_rows(), 3);
+        assert_eq!(server.n_classes(), 3);
+    }
+}
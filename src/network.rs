@@ -147,10 +147,8 @@ impl TaskManager {
     }
 }
 
-pub fn par_run_network_trivial<CMP>(network: &[Task], cmp: CMP, vs: &[CMP::Item])
-where
-    CMP: AsyncComparator + Sync + Send + Clone,
-{
+/// Group the tasks of a network (sorted by level) into one vector per level.
+fn group_by_level(network: &[Task]) -> Vec<Vec<Task>> {
     let mut grouped_network = vec![];
     let mut current_level = 0usize;
     let mut task_i = 0usize;
@@ -166,6 +164,14 @@ where
         grouped_network.push(tmp);
         current_level += 1;
     }
+    grouped_network
+}
+
+pub fn par_run_network_trivial<CMP>(network: &[Task], cmp: CMP, vs: &[CMP::Item])
+where
+    CMP: AsyncComparator + Sync + Send + Clone,
+{
+    let grouped_network = group_by_level(network);
 
     // println!("{:?}", grouped_network);
     for tasks in grouped_network {
@@ -175,6 +181,21 @@ where
     }
 }
 
+/// Run the network level by level where every level is given to the comparator as one batch,
+/// see `AsyncComparator::compare_batch`.
+pub fn par_run_network_batched<CMP>(network: &[Task], cmp: CMP, vs: &[CMP::Item])
+where
+    CMP: AsyncComparator + Sync + Send + Clone,
+{
+    for tasks in group_by_level(network) {
+        let pairs: Vec<_> = tasks
+            .iter()
+            .map(|task| (&vs[task.v0], &vs[task.v1]))
+            .collect();
+        cmp.compare_batch(&pairs);
+    }
+}
+
 pub fn par_run_network<CMP>(network: &[Task], cmp: CMP, vs: &[CMP::Item])
 where
     CMP: AsyncComparator + Sync + Send + Clone,
@@ -390,6 +411,34 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_network_batched() {
+        for (d, k) in [(10, 1), (20, 3), (100, 5)] {
+            let pb: PathBuf = [
+                env!("CARGO_MANIFEST_DIR"),
+                "data",
+                &format!("network-{}-{}.csv", d, k),
+            ]
+            .iter()
+            .collect();
+            let network = load_network(pb.as_path()).unwrap();
+
+            let cmp = AsyncClearComparator::new_with_counter();
+            let mut rng = rand::thread_rng();
+            let actual: Vec<_> = (0..d).map(|_| rng.gen::<u64>()).collect();
+            let a_actual: Vec<_> = actual.iter().map(|x| Arc::new(Mutex::new(*x))).collect();
+            par_run_network_batched(&network, cmp.clone(), &a_actual);
+            assert_eq!(cmp.compare_count(), network.len());
+
+            let mut expected = actual.clone();
+            expected.sort();
+            let a_actual: Vec<_> = a_actual.into_iter().map(|x| *x.lock().unwrap()).collect();
+            let mut left = a_actual.split_at(k).0.to_vec();
+            left.sort();
+            assert_eq!(left, expected.split_at(k).0);
+        }
+    }
+
     fn test_network(d: usize, k: usize, trivial: bool) {
         let pb: PathBuf = [
             env!("CARGO_MANIFEST_DIR"),
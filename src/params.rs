@@ -0,0 +1,188 @@
+use crate::KnnError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use tfhe::shortint::prelude::*;
+
+/// The name of the preset that is used when none is given.
+pub const DEFAULT_PRESET: &str = "msg32";
+
+/// The parameters of the `msg32` preset, which are used in the paper.
+pub const DEFAULT_PARAMS: Parameters = Parameters {
+    message_modulus: MessageModulus(32),
+    carry_modulus: CarryModulus(1),
+    ..PARAM_MESSAGE_2_CARRY_3
+};
+
+/// Small parameters that are only meant for fast tests,
+/// `NoiseModel` gives a high failure probability with a message modulus of 32.
+pub const TEST_PARAMS: Parameters = Parameters {
+    lwe_dimension: LweDimension(742),
+    glwe_dimension: GlweDimension(1),
+    polynomial_size: PolynomialSize(2048),
+    lwe_modular_std_dev: StandardDev(0.000007069849454709433),
+    glwe_modular_std_dev: StandardDev(0.00000000000000029403601535432533),
+    pbs_level: DecompositionLevelCount(6),
+    pbs_base_log: DecompositionBaseLog(3),
+    ks_level: DecompositionLevelCount(6),
+    ks_base_log: DecompositionBaseLog(3),
+    pfks_level: DecompositionLevelCount(6),
+    pfks_base_log: DecompositionBaseLog(3),
+    pfks_modular_std_dev: StandardDev(0.00000000000000029403601535432533),
+    cbs_level: DecompositionLevelCount(0),
+    cbs_base_log: DecompositionBaseLog(0),
+    message_modulus: MessageModulus(32),
+    carry_modulus: CarryModulus(1),
+};
+
+/// A named parameter set.
+/// The built-in sets use the carry space of the original tfhe-rs set as message space,
+/// so the noise budget of a bootstrap does not change.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ParamPreset {
+    pub name: String,
+    pub params: Parameters,
+}
+
+impl ParamPreset {
+    pub fn new(name: &str, params: Parameters) -> Self {
+        Self {
+            name: name.to_string(),
+            params,
+        }
+    }
+}
+
+/// The parameter presets that can be selected by name,
+/// ordered from the cheapest to the most expensive bootstrap.
+#[derive(Clone, Debug)]
+pub struct PresetRegistry {
+    presets: Vec<ParamPreset>,
+}
+
+impl Default for PresetRegistry {
+    fn default() -> Self {
+        PresetRegistry::builtin()
+    }
+}
+
+impl PresetRegistry {
+    /// A registry without any preset.
+    pub fn new() -> Self {
+        Self { presets: vec![] }
+    }
+
+    /// The presets that come with the library.
+    pub fn builtin() -> Self {
+        let with_message_modulus = |message_modulus, params| Parameters {
+            message_modulus: MessageModulus(message_modulus),
+            carry_modulus: CarryModulus(1),
+            ..params
+        };
+        Self {
+            presets: vec![
+                ParamPreset::new("test", TEST_PARAMS),
+                ParamPreset::new("msg16", with_message_modulus(16, PARAM_MESSAGE_2_CARRY_2)),
+                ParamPreset::new(DEFAULT_PRESET, DEFAULT_PARAMS),
+                ParamPreset::new("msg64", with_message_modulus(64, PARAM_MESSAGE_3_CARRY_3)),
+                ParamPreset::new("msg128", with_message_modulus(128, PARAM_MESSAGE_3_CARRY_4)),
+            ],
+        }
+    }
+
+    pub fn presets(&self) -> &[ParamPreset] {
+        &self.presets
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.presets.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ParamPreset, KnnError> {
+        self.presets
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| KnnError::UnknownPreset {
+                name: name.to_string(),
+                known: self.names().join(", "),
+            })
+    }
+
+    /// The parameters of the preset called `name`.
+    pub fn params(&self, name: &str) -> Result<Parameters, KnnError> {
+        Ok(self.get(name)?.params)
+    }
+
+    /// Add `preset` at the end, or replace the preset with the same name.
+    pub fn insert(&mut self, preset: ParamPreset) {
+        match self.presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(p) => *p = preset,
+            None => self.presets.push(preset),
+        }
+    }
+
+    /// Insert the custom presets from a JSON file that contains a list of presets,
+    /// `save_file` can be used to create an example.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), KnnError> {
+        let bytes = fs::read(path).map_err(KnnError::PresetFile)?;
+        let presets: Vec<ParamPreset> =
+            serde_json::from_slice(&bytes).map_err(|e| KnnError::PresetFile(e.into()))?;
+        for preset in presets {
+            self.insert(preset);
+        }
+        Ok(())
+    }
+
+    /// Write all the presets to a JSON file.
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> Result<(), KnnError> {
+        let json =
+            serde_json::to_vec_pretty(&self.presets).map_err(|e| KnnError::PresetFile(e.into()))?;
+        fs::write(path, json).map_err(KnnError::PresetFile)
+    }
+}
+
+/// The parameters of the built-in preset called `name`.
+pub fn preset(name: &str) -> Result<Parameters, KnnError> {
+    PresetRegistry::builtin().params(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(preset(DEFAULT_PRESET).unwrap().message_modulus.0, 32);
+        assert!(matches!(
+            preset("msg8"),
+            Err(KnnError::UnknownPreset { .. })
+        ));
+
+        // a custom preset replaces the built-in one with the same name
+        let mut presets = PresetRegistry::builtin();
+        let n = presets.presets().len();
+        presets.insert(ParamPreset::new(
+            "msg64",
+            Parameters {
+                message_modulus: MessageModulus(64),
+                carry_modulus: CarryModulus(1),
+                ..PARAM_MESSAGE_2_CARRY_4
+            },
+        ));
+        presets.insert(ParamPreset::new("custom", TEST_PARAMS));
+        assert_eq!(presets.presets().len(), n + 1);
+
+        let path = std::env::temp_dir().join("ppknn_test_presets.json");
+        presets.save_file(&path).unwrap();
+        let mut loaded = PresetRegistry::builtin();
+        loaded.load_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.names(), presets.names());
+        assert_eq!(
+            loaded.params("msg64").unwrap().polynomial_size,
+            PARAM_MESSAGE_2_CARRY_4.polynomial_size
+        );
+        assert_eq!(loaded.params("custom").unwrap().lwe_dimension.0, 742);
+    }
+}
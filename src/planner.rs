@@ -0,0 +1,375 @@
+use crate::{
+    BatcherSort, ClearComparator, DistanceAnalysis, KnnError, PresetRegistry, SelectionNetwork,
+    ValueRange,
+};
+use std::f64::consts::{LN_2, PI};
+use std::time::{Duration, Instant};
+use tfhe::shortint::prelude::*;
+
+/// The variance of the noise that every step of `keyswitch_programmable_bootstrap` adds,
+/// as a fraction of the torus, using the usual TFHE noise formulas:
+/// the noise of the key switching and bootstrapping keys
+/// plus the error of the gadget decomposition, where the secret keys are binary.
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseModel {
+    pub keyswitch: f64,
+    pub modulus_switch: f64,
+    pub bootstrap: f64,
+}
+
+impl NoiseModel {
+    pub fn new(params: Parameters) -> Self {
+        let n = params.lwe_dimension.0 as f64;
+        let big_n = params.polynomial_size.0 as f64;
+        let k = params.glwe_dimension.0 as f64;
+        // the variance of the digits and of the truncated part times E[s^2] = 1/2
+        let decomposition = |base_log: usize, level: usize| {
+            let base = (base_log as f64).exp2();
+            (
+                level as f64 * (base * base + 2.0) / 12.0,
+                (-2.0 * (base_log * level) as f64).exp2() / 24.0,
+            )
+        };
+
+        let (ks_digits, ks_rest) = decomposition(params.ks_base_log.0, params.ks_level.0);
+        let keyswitch = k * big_n * (ks_digits * params.lwe_modular_std_dev.0.powi(2) + ks_rest);
+
+        // every coefficient is rounded to a multiple of 1/2N
+        let modulus_switch = (1.0 + n / 2.0) / (48.0 * big_n * big_n);
+
+        let (pbs_digits, pbs_rest) = decomposition(params.pbs_base_log.0, params.pbs_level.0);
+        let bootstrap = n
+            * ((k + 1.0) * big_n * pbs_digits * params.glwe_modular_std_dev.0.powi(2)
+                + (1.0 + k * big_n / 2.0) * pbs_rest);
+
+        Self {
+            keyswitch,
+            modulus_switch,
+            bootstrap,
+        }
+    }
+
+    /// The base 2 logarithm of the probability that a bootstrap rounds to the wrong value,
+    /// where the input noise has variance `input` before the key switch
+    /// and the plaintext modulus is `modulus` with one bit of padding.
+    pub fn log2_failure(&self, params: Parameters, input: f64, modulus: u64) -> f64 {
+        let variance = input + self.keyswitch + self.modulus_switch;
+        let half_delta = 1.0 / (4.0 * modulus as f64 * params.carry_modulus.0 as f64);
+        log2_erfc(half_delta / (2.0 * variance).sqrt())
+    }
+}
+
+/// `log2(erfc(x))` for `x >= 0`, using the approximation 7.1.26 of Abramowitz and Stegun
+/// for small `x` and the asymptotic expansion otherwise, so that tiny probabilities do not underflow.
+fn log2_erfc(x: f64) -> f64 {
+    if x < 3.0 {
+        let t = 1.0 / (1.0 + 0.3275911 * x);
+        let poly = t
+            * (0.254829592
+                + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+        (poly.ln() - x * x) / LN_2
+    } else {
+        let x2 = x * x;
+        let series = 1.0 - 1.0 / (2.0 * x2) + 3.0 / (4.0 * x2 * x2);
+        (series.ln() - x2 - (x * PI.sqrt()).ln()) / LN_2
+    }
+}
+
+/// The shape of a classification task, i.e., what the planner needs to know about the data.
+#[derive(Clone, Copy, Debug)]
+pub struct DatasetShape {
+    pub n_features: usize,
+    /// The range of every feature, both in the model and in the queries.
+    pub value_range: ValueRange,
+    pub n_classes: usize,
+    pub model_size: usize,
+    pub k: usize,
+}
+
+/// The number of bootstraps of one query, split by the noise of their inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bootstraps {
+    /// Reducing the precision of the distances.
+    pub lowering: usize,
+    /// The selection network and the majority vote.
+    pub comparisons: usize,
+}
+
+impl Bootstraps {
+    pub fn total(&self) -> usize {
+        self.lowering + self.comparisons
+    }
+}
+
+impl DatasetShape {
+    /// The overflow analysis where every feature of the model and the query is in `value_range`.
+    pub fn analysis(&self) -> DistanceAnalysis {
+        DistanceAnalysis {
+            model_ranges: vec![self.value_range; self.n_features],
+            query_ranges: vec![self.value_range; self.n_features],
+        }
+    }
+
+    /// The number of bootstraps to find the majority class of `model_size` rows
+    /// with `network`, where the distances are computed with `dist_modulus`
+    /// and sorted with the message modulus of `params`.
+    pub fn bootstraps(
+        &self,
+        params: Parameters,
+        dist_modulus: u64,
+        network: SelectionNetwork,
+    ) -> Bootstraps {
+        let (d, k) = (self.model_size, self.k);
+        // the precision of every distance is reduced with one bootstrap
+        let lowering = if dist_modulus == params.message_modulus.0 as u64 {
+            0
+        } else {
+            d
+        };
+        // every comparison selects the distance and the label
+        let selection = match network {
+            SelectionNetwork::Batcher => 2 * batcher_comparisons(d, k),
+            SelectionNetwork::Rank => d * (d - 1) / 2 + 2 * k * d,
+        };
+        // one indicator per class and label, then a tournament over the counts
+        let majority = self.n_classes * k + 2 * self.n_classes.saturating_sub(1);
+        Bootstraps {
+            lowering,
+            comparisons: selection + majority,
+        }
+    }
+
+    /// The noise variance of a distance before its precision is reduced:
+    /// the norm of the query is a fresh LWE ciphertext and the inner product
+    /// multiplies the GLWE noise by twice the model row.
+    pub fn distance_noise(&self, params: Parameters) -> f64 {
+        let max = self.value_range.max as f64;
+        params.lwe_modular_std_dev.0.powi(2)
+            + 4.0 * self.n_features as f64 * max * max * params.glwe_modular_std_dev.0.powi(2)
+    }
+}
+
+/// The number of comparisons of the truncated Batcher network over `d` elements,
+/// it does not depend on the input so it is counted on plaintext.
+pub fn batcher_comparisons(d: usize, k: usize) -> usize {
+    let mut vs = vec![0u64; d];
+    let sorter = BatcherSort::new_k(k, ClearComparator::new(), false);
+    sorter.sort(&mut vs);
+    sorter.comparisons()
+}
+
+/// The output of `plan`.
+#[derive(Clone, Debug)]
+pub struct Plan {
+    /// The name of the preset in the `PresetRegistry`.
+    pub name: String,
+    pub params: Parameters,
+    pub network: SelectionNetwork,
+    /// The `initial_modulus` for `setup_with_modulus`.
+    pub dist_modulus: u64,
+    pub max_distance: u64,
+    pub bootstraps: Bootstraps,
+    /// The base 2 logarithm of the failure probability of one comparison bootstrap.
+    pub log2_pbs_failure: f64,
+    /// The probability that at least one bootstrap fails during one query.
+    pub failure_probability: f64,
+}
+
+impl Plan {
+    /// Predict the server time of one query from the time of one bootstrap,
+    /// e.g., from `measure_pbs`, assuming the bootstraps are spread evenly over `threads`.
+    /// The distance computation is not included since it is cheap in comparison.
+    pub fn predict_runtime(&self, pbs_time: Duration, threads: usize) -> Duration {
+        pbs_time * self.bootstraps.total() as u32 / threads.max(1) as u32
+    }
+}
+
+/// The probability that at least one bootstrap fails,
+/// every element of `bootstraps` is the base 2 logarithm of the failure probability
+/// and the number of bootstraps with this probability, which fail independently.
+pub fn failure_probability(bootstraps: &[(f64, usize)]) -> f64 {
+    let log_success: f64 = bootstraps
+        .iter()
+        .map(|(log2_p, n)| *n as f64 * (-log2_p.exp2()).ln_1p())
+        .sum();
+    -log_success.exp_m1()
+}
+
+/// Pick the first preset in `presets` that fits `shape`
+/// and where the failure probability of one query is at most `max_failure`.
+/// The failure probability comes from `NoiseModel`, where the input of a comparison
+/// is the difference of two bootstrapped values. The noise that the accumulators
+/// built from encrypted inputs add at every level of the network is not modelled.
+/// The distance modulus is picked by the overflow analysis so that every distance is smaller
+/// than half of it, then the distances are also in the lower half of the message modulus
+/// after `lower_precision`, which is what the comparisons need.
+/// It may be higher than the message modulus in which case the distances lose precision.
+/// The rank selection is used if it fits in the message modulus and needs fewer bootstraps.
+pub fn plan(
+    presets: &PresetRegistry,
+    shape: &DatasetShape,
+    max_failure: f64,
+) -> Result<Plan, KnnError> {
+    if shape.k == 0 || shape.k > shape.model_size {
+        return Err(KnnError::KOutOfRange {
+            k: shape.k,
+            n_rows: shape.model_size,
+        });
+    }
+    let analysis = shape.analysis();
+    presets
+        .presets()
+        .iter()
+        .filter(|preset| {
+            let t = preset.params.message_modulus.0;
+            shape.n_features < preset.params.polynomial_size.0
+                && shape.n_classes <= t
+                && shape.k < t / 2
+        })
+        .filter_map(|preset| {
+            let params = preset.params;
+            let dist_modulus = analysis.pick_dist_modulus(params);
+            let mut networks = vec![SelectionNetwork::Batcher];
+            if shape.model_size <= params.message_modulus.0 {
+                networks.push(SelectionNetwork::Rank);
+            }
+            let (network, bootstraps) = networks
+                .into_iter()
+                .map(|n| (n, shape.bootstraps(params, dist_modulus, n)))
+                .min_by_key(|(_, bootstraps)| bootstraps.total())
+                .unwrap();
+
+            let noise = NoiseModel::new(params);
+            let distance_noise = shape.distance_noise(params);
+            let log2_lowering = noise.log2_failure(params, distance_noise, dist_modulus);
+            // without lowering, the distances are compared directly
+            let value_noise = if bootstraps.lowering == 0 {
+                distance_noise
+            } else {
+                noise.bootstrap
+            };
+            let log2_pbs_failure =
+                noise.log2_failure(params, 2.0 * value_noise, params.message_modulus.0 as u64);
+            let failure_probability = failure_probability(&[
+                (log2_lowering, bootstraps.lowering),
+                (log2_pbs_failure, bootstraps.comparisons),
+            ]);
+            (failure_probability <= max_failure).then(|| Plan {
+                name: preset.name.clone(),
+                params,
+                network,
+                dist_modulus,
+                max_distance: analysis.max_distance(),
+                bootstraps,
+                log2_pbs_failure,
+                failure_probability,
+            })
+        })
+        .next()
+        .ok_or(KnnError::NoParameters {
+            n_features: shape.n_features,
+            n_classes: shape.n_classes,
+            k: shape.k,
+        })
+}
+
+/// Measure the time of one bootstrap with `params`,
+/// the keys are generated first so this takes a few seconds.
+pub fn measure_pbs(params: Parameters, repetitions: usize) -> Duration {
+    let (client_key, server_key) = gen_keys(params);
+    let mut ct = client_key.encrypt(1);
+    let start = Instant::now();
+    for _ in 0..repetitions {
+        server_key.keyswitch_bootstrap_assign(&mut ct);
+    }
+    start.elapsed() / repetitions.max(1) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::TEST_PARAM;
+    use crate::ParamPreset;
+
+    #[test]
+    fn test_noise() {
+        // erfc(0) = 1, erfc(1) = 0.157299..., erfc(5) = 1.53745...e-12
+        assert!(log2_erfc(0.0).abs() < 1e-6);
+        assert!((log2_erfc(1.0) - 0.157299207f64.log2()).abs() < 1e-5);
+        assert!((log2_erfc(5.0) - 1.5374597944e-12f64.log2()).abs() < 1e-3);
+        // both approximations agree where they meet
+        assert!((log2_erfc(2.999999) - log2_erfc(3.0)).abs() < 0.01);
+
+        assert_eq!(failure_probability(&[(-40.0, 0)]), 0.0);
+        let p = failure_probability(&[(-40.0, 1 << 19), (-41.0, 1 << 20)]);
+        assert!((p - (-20f64).exp2()).abs() < 1e-9);
+
+        let noise = NoiseModel::new(TEST_PARAM);
+        assert!((noise.keyswitch / 3.37928e-6 - 1.0).abs() < 1e-4);
+        assert!((noise.modulus_switch / 1.84774e-6 - 1.0).abs() < 1e-4);
+        assert!((noise.bootstrap / 4.61144e-7 - 1.0).abs() < 1e-4);
+        // a larger message modulus leaves less room for the noise
+        let log2_16 = noise.log2_failure(TEST_PARAM, 2.0 * noise.bootstrap, 16);
+        let log2_32 = noise.log2_failure(TEST_PARAM, 2.0 * noise.bootstrap, 32);
+        assert!((log2_16 + 31.65).abs() < 0.01);
+        assert!((log2_32 + 9.26).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(batcher_comparisons(4, 4), 5);
+
+        let with_message_modulus = |message_modulus| Parameters {
+            message_modulus: MessageModulus(message_modulus),
+            ..TEST_PARAM
+        };
+        let mut presets = PresetRegistry::new();
+        presets.insert(ParamPreset::new("test16", with_message_modulus(16)));
+        presets.insert(ParamPreset::new("test32", with_message_modulus(32)));
+
+        // the squared distance is at most 9 * 2^2 = 36,
+        // so it is at most 36 / 8 = 4 < 16 / 2 after lowering to the message modulus
+        let shape = DatasetShape {
+            n_features: 9,
+            value_range: ValueRange::new(0, 2),
+            n_classes: 2,
+            model_size: 40,
+            k: 3,
+        };
+        let plan = plan(&presets, &shape, 1.0).unwrap();
+        assert_eq!(plan.name, "test16");
+        assert_eq!(plan.max_distance, 36);
+        assert_eq!(plan.dist_modulus, 128);
+        assert_eq!(plan.network, SelectionNetwork::Batcher);
+        assert_eq!(
+            plan.bootstraps,
+            Bootstraps {
+                lowering: 40,
+                comparisons: 2 * batcher_comparisons(40, 3) + 2 * 3 + 2
+            }
+        );
+        assert!(plan.failure_probability > 0.0 && plan.failure_probability < 1.0);
+
+        // too many classes for the smaller message modulus
+        let plan = super::plan(
+            &presets,
+            &DatasetShape {
+                n_classes: 20,
+                ..shape
+            },
+            1.0,
+        )
+        .unwrap();
+        assert_eq!(plan.name, "test32");
+
+        // the failure bound cannot be met
+        assert!(matches!(
+            super::plan(&presets, &shape, 0.0),
+            Err(KnnError::NoParameters { .. })
+        ));
+        assert!(matches!(
+            super::plan(&presets, &DatasetShape { k: 41, ..shape }, 1.0),
+            Err(KnnError::KOutOfRange { k: 41, n_rows: 40 })
+        ));
+    }
+}
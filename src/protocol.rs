@@ -0,0 +1,360 @@
+use crate::message::{Message, MessageKind, Query, QueryOutput, QueryResult};
+use crate::{EvaluationKeys, KnnClient, KnnServer, Registry, SelectionNetwork};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// The largest frame that is accepted for a message of `kind`,
+/// larger frames are rejected before their body is read.
+/// The evaluation keys grow with the parameters so they get the most room.
+pub fn max_frame_len(kind: MessageKind) -> u64 {
+    match kind {
+        MessageKind::ClientKey => 16 << 20,
+        MessageKind::EvaluationKeys => 4 << 30,
+        MessageKind::Query => 16 << 20,
+        MessageKind::QueryResult => 256 << 20,
+        MessageKind::Model => 1 << 30,
+    }
+}
+
+fn invalid_data(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Write `msg` as one frame,
+/// i.e., the serialized message prefixed by its length as a little-endian u64.
+pub fn write_frame<M: Message, W: Write>(mut writer: W, msg: &M) -> io::Result<()> {
+    let bytes = msg.to_bytes().map_err(invalid_data)?;
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Read one frame written by `write_frame`,
+/// the version and the kind of the message are checked when it is deserialized.
+pub fn read_frame<M: Message, R: Read>(mut reader: R) -> io::Result<M> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    let max_len = max_frame_len(M::KIND);
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {len} bytes is too large for {:?}, at most {max_len} bytes are accepted",
+                M::KIND
+            ),
+        ));
+    }
+    // the buffer grows with the bytes that arrive instead of trusting the length
+    let mut bytes = vec![];
+    (&mut reader).take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("frame of {len} bytes ended after {} bytes", bytes.len()),
+        ));
+    }
+    M::from_bytes(&bytes).map_err(invalid_data)
+}
+
+/// Run one query on the server, `k` overrides `query.k`.
+/// The majority vote needs single block labels and `k` smaller than half the message modulus,
+/// so that the vote counts stay in the lower half of the plaintext space.
+pub fn answer(
+    server: &Arc<RwLock<KnnServer>>,
+    k: usize,
+    network: SelectionNetwork,
+    query: &Query,
+) -> io::Result<QueryResult> {
+    let (n_rows, label_blocks, message_modulus) = {
+        let server = server.read().unwrap();
+        (
+            server.n_rows(),
+            server.label_blocks(),
+            server.params().message_modulus.0,
+        )
+    };
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    if k == 0 || k > n_rows {
+        return invalid(format!("k={k} must be between 1 and {n_rows}"));
+    }
+    if query.output == QueryOutput::Majority {
+        if label_blocks != 1 {
+            return invalid(format!(
+                "the majority vote needs single block labels but the labels have {label_blocks} blocks"
+            ));
+        }
+        if 2 * k >= message_modulus {
+            return invalid(format!(
+                "k={k} is too large for the majority vote, it must be smaller than {}",
+                message_modulus / 2
+            ));
+        }
+    }
+    let items = KnnServer::nearest(server, k, network, &query.glwe, &query.lwe);
+    Ok(match query.output {
+        QueryOutput::Nearest => QueryResult::Nearest {
+            items,
+            label_blocks,
+        },
+        QueryOutput::Majority => {
+            let labels: Vec<_> = items.iter().map(|item| item.class().clone()).collect();
+            QueryResult::Majority(server.read().unwrap().majority(&labels)?)
+        }
+    })
+}
+
+/// Answer the queries from `reader` with `f` until the client closes the connection.
+/// A query that fails is answered with `QueryResult::Error` and the connection stays open.
+fn answer_loop<F>(reader: &mut impl Read, writer: &mut impl Write, f: F) -> io::Result<()>
+where
+    F: Fn(&Query) -> io::Result<QueryResult>,
+{
+    loop {
+        let query: Query = match read_frame(&mut *reader) {
+            Ok(query) => query,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let result = f(&query).unwrap_or_else(|e| QueryResult::Error(e.to_string()));
+        write_frame(&mut *writer, &result)?;
+    }
+}
+
+/// Answer the queries on `stream` until the client closes the connection,
+/// the model in the query is ignored since there is only one.
+pub fn handle_connection(server: &Arc<RwLock<KnnServer>>, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    answer_loop(&mut reader, &mut writer, |query| {
+        answer(server, query.k, SelectionNetwork::Batcher, query)
+    })
+}
+
+/// Like `handle_connection` but the queries are routed to the models in `registry`.
+/// The first frame on the connection must be the evaluation keys of the client,
+/// they are used for a new session that is closed together with the connection.
+/// If the keys are rejected, every query is answered with the reason.
+pub fn handle_registry_connection(registry: &Registry, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let keys: EvaluationKeys = read_frame(&mut reader)?;
+    let session = match registry.open_session(keys) {
+        Ok(session) => session,
+        Err(e) => {
+            let msg = e.to_string();
+            return answer_loop(&mut reader, &mut writer, |_| {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg.clone()))
+            });
+        }
+    };
+    let out = answer_loop(&mut reader, &mut writer, |query| {
+        registry.answer(session, query)
+    });
+    registry.close_session(session);
+    out
+}
+
+/// Accept connections on `listener` forever, every connection is handled in its own thread.
+pub fn serve(listener: TcpListener, server: Arc<RwLock<KnnServer>>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = server.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = handle_connection(&server, stream) {
+                eprintln!("[ERROR] connection {peer:?} failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Accept connections on `listener` forever and route the queries with `registry`.
+pub fn serve_registry(listener: TcpListener, registry: Arc<Registry>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let registry = registry.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = handle_registry_connection(&registry, stream) {
+                eprintln!("[ERROR] connection {peer:?} failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Encrypt `target`, send it to the server at `addr` and wait for the result.
+/// The result is still encrypted, only `client` can decrypt it.
+pub fn send_query<A: ToSocketAddrs>(
+    addr: A,
+    client: &mut KnnClient,
+    target: &[u64],
+    k: usize,
+    output: QueryOutput,
+) -> io::Result<QueryResult> {
+    let stream = TcpStream::connect(addr)?;
+    send_query_on(&stream, client, "", target, k, output)
+}
+
+/// Connect to a server that hosts a `Registry` and start a session with `keys`,
+/// the queries are sent with `send_query_on`.
+pub fn open_session<A: ToSocketAddrs>(addr: A, keys: &EvaluationKeys) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    write_frame(BufWriter::new(&stream), keys)?;
+    Ok(stream)
+}
+
+/// Encrypt `target`, send it over an existing connection and wait for the result.
+pub fn send_query_on(
+    stream: &TcpStream,
+    client: &mut KnnClient,
+    model: &str,
+    target: &[u64],
+    k: usize,
+    output: QueryOutput,
+) -> io::Result<QueryResult> {
+    let (glwe, lwe) = client.make_query(target);
+    write_frame(
+        BufWriter::new(stream),
+        &Query {
+            glwe,
+            lwe,
+            k,
+            output,
+            model: model.to_string(),
+        },
+    )?;
+    read_frame(BufReader::new(stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::TEST_PARAM;
+    use crate::setup_with_data;
+
+    #[test]
+    fn test_frame() {
+        let mut buf = vec![];
+        write_frame(&mut buf, &QueryResult::Error(String::new())).unwrap();
+        assert_eq!(
+            u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            buf.len() as u64 - 8
+        );
+        assert!(read_frame::<QueryResult, _>(&buf[..]).is_ok());
+        // truncated frames and the wrong kind are rejected
+        assert!(read_frame::<QueryResult, _>(&buf[..buf.len() - 1]).is_err());
+        assert!(read_frame::<Query, _>(&buf[..]).is_err());
+
+        // the length is checked against the kind before the body is read
+        let too_long = (max_frame_len(MessageKind::Query) + 1).to_le_bytes();
+        let err = read_frame::<Query, _>(&too_long[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut short = (1u64 << 20).to_le_bytes().to_vec();
+        short.extend_from_slice(&[0; 16]);
+        let err = read_frame::<Query, _>(&short[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_localhost() {
+        let data = vec![
+            vec![0, 0, 0, 0u64],
+            vec![1, 0, 0, 0],
+            vec![2, 0, 0, 0],
+            vec![3, 0, 0, 0],
+        ];
+        let labels = vec![1, 1, 2, 2];
+        let (mut client, server) = setup_with_data(TEST_PARAM, &data, &labels, 32).unwrap();
+        let server = Arc::new(RwLock::new(server));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(&server, stream).unwrap();
+            }
+        });
+
+        match send_query(addr, &mut client, &[0, 0, 0, 0], 2, QueryOutput::Nearest).unwrap() {
+            QueryResult::Nearest {
+                items,
+                label_blocks,
+            } => {
+                assert_eq!(label_blocks, 1);
+                let actual: Vec<_> = items.iter().map(|item| item.decrypt(&client.key)).collect();
+                assert_eq!(actual, vec![(0, 1), (1, 1)]);
+            }
+            _ => panic!("expected the nearest items"),
+        }
+
+        // the connection stays open after a rejected query
+        let stream = TcpStream::connect(addr).unwrap();
+        let output = QueryOutput::Majority;
+        match send_query_on(&stream, &mut client, "", &[3, 0, 0, 0], 5, output).unwrap() {
+            QueryResult::Error(e) => assert_eq!(e, "k=5 must be between 1 and 4"),
+            _ => panic!("expected an error"),
+        }
+        match send_query_on(&stream, &mut client, "", &[3, 0, 0, 0], 3, output).unwrap() {
+            QueryResult::Majority(ct) => assert_eq!(client.key.decrypt(&ct), 2),
+            _ => panic!("expected the majority class"),
+        }
+        drop(stream);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let registry = Registry::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_registry_connection(&registry, stream).unwrap();
+        });
+
+        // the server answers with an error instead of panicking on the keys
+        let (mut client, mut keys) = KnnClient::keygen(TEST_PARAM, 32);
+        keys.dist_delta = 3;
+        let stream = open_session(addr, &keys).unwrap();
+        let output = QueryOutput::Nearest;
+        for _ in 0..2 {
+            match send_query_on(&stream, &mut client, "", &[0, 0, 0, 0], 1, output).unwrap() {
+                QueryResult::Error(e) => assert!(e.starts_with("invalid evaluation keys")),
+                _ => panic!("expected an error"),
+            }
+        }
+        drop(stream);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_majority_k() {
+        // 16 rows so that k can reach half the message modulus
+        let data: Vec<_> = (0..16).map(|i| vec![i % 4, 0, 0, 0u64]).collect();
+        let labels = vec![0; 16];
+        let (mut client, server) = setup_with_data(TEST_PARAM, &data, &labels, 32).unwrap();
+        let server = Arc::new(RwLock::new(server));
+
+        let (glwe, lwe) = client.make_query(&[0, 0, 0, 0]);
+        let query = Query {
+            glwe,
+            lwe,
+            k: 16,
+            output: QueryOutput::Majority,
+            model: String::new(),
+        };
+        let err = answer(&server, 16, SelectionNetwork::Batcher, &query).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // multi block labels cannot be counted
+        server.write().unwrap().set_labels(&[40; 16]).unwrap();
+        let err = answer(&server, 3, SelectionNetwork::Batcher, &query).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
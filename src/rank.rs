@@ -0,0 +1,169 @@
+use crate::server::{with_polymul_fft, KnnServer};
+use crate::EncItem;
+use rayon::prelude::*;
+use std::sync::{Arc, Mutex, RwLock};
+use tfhe::shortint::prelude::*;
+
+/// Select the `k` smallest elements by ranking instead of sorting.
+/// All the pairwise comparisons are independent, so they form a single layer of depth one.
+/// The rank of every element is the sum of its comparison bits
+/// and the element with rank `r` is moved to the position `r` obliviously,
+/// together with its payload.
+/// Since the ranks are encrypted under the sorting plaintext modulus,
+/// the input length cannot be higher than the message modulus.
+pub struct RankSelect {
+    k: usize,
+    server: Arc<RwLock<KnnServer>>,
+    params: Parameters,
+    counter: Arc<Mutex<usize>>,
+}
+
+impl RankSelect {
+    /// Create an instance of the rank based selection where the output length is `k`.
+    pub fn par_new_k(k: usize, server: Arc<RwLock<KnnServer>>, params: Parameters) -> Self {
+        Self {
+            k,
+            server,
+            params,
+            counter: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Run the selection, the output has the same shape as `BatcherSort::par_sort`,
+    /// i.e., the first `k` elements of `vs` are the smallest ones in ascending order.
+    /// The remaining elements are left untouched.
+    pub fn par_select(&self, vs: &[Arc<Mutex<EncItem>>]) {
+        let d = vs.len();
+        assert!(self.k <= d);
+        assert!(
+            d <= self.params.message_modulus.0,
+            "the input length must not exceed the message modulus, see SelectionNetwork::check"
+        );
+        assert!(
+            vs.iter().all(|v| v.lock().unwrap().low_blocks.is_empty()),
+            "radix distances are not supported"
+        );
+        let server = self.server.read().unwrap();
+
+        // the comparison bit of every pair (i, j) where i < j, i.e., [v_j < v_i],
+        // every item is only locked while its value is copied
+        let value = |i: usize| vs[i].lock().unwrap().value.clone();
+        let pairs: Vec<_> = (0..d)
+            .flat_map(|i| (i + 1..d).map(move |j| (i, j)))
+            .collect();
+        let bits: Vec<_> = pairs
+            .par_iter()
+            .map(|(i, j)| server.less_than(&value(*j), &value(*i)))
+            .collect();
+        *self.counter.lock().unwrap() += pairs.len();
+
+        // rank_i = #{j < i : v_j <= v_i} + #{j > i : v_j < v_i},
+        // so that ties are broken by the index and the ranks form a permutation
+        let one = server.trivially_encrypt(1);
+        let mut ranks: Vec<_> = (0..d).map(|_| server.trivially_encrypt(0)).collect();
+        for ((i, j), bit) in pairs.iter().zip(bits.iter()) {
+            server.raw_add_assign(&mut ranks[*i], bit);
+            let not_bit = server.raw_sub(&one, bit);
+            server.raw_add_assign(&mut ranks[*j], &not_bit);
+        }
+
+        // the output at position r is the sum of all elements masked by [rank_i == r]
+        let outputs: Vec<_> = (0..self.k)
+            .into_par_iter()
+            .map(|r| {
+                let selected: Vec<_> = (0..d)
+                    .into_par_iter()
+                    .map(|i| {
+                        let item = vs[i].lock().unwrap().clone();
+                        with_polymul_fft(self.params, |fft, stack| {
+                            let mut select = |ct: &Ciphertext| {
+                                server.select_eq_with_fft(&ranks[i], r as u64, ct, fft, stack)
+                            };
+                            EncItem::new_with_payload(
+                                select(&item.value),
+                                item.payload.iter().map(&mut select).collect(),
+                            )
+                        })
+                    })
+                    .collect();
+                let mut out = EncItem::new_with_payload(
+                    server.trivially_encrypt(0),
+                    selected[0]
+                        .payload
+                        .iter()
+                        .map(|_| server.trivially_encrypt(0))
+                        .collect(),
+                );
+                for item in selected.iter() {
+                    server.raw_add_assign(&mut out.value, &item.value);
+                    for (x, y) in out.payload.iter_mut().zip(item.payload.iter()) {
+                        server.raw_add_assign(x, y);
+                    }
+                }
+                out
+            })
+            .collect();
+
+        for (v, out) in vs.iter().zip(outputs) {
+            *v.lock().unwrap() = out;
+        }
+    }
+
+    /// Output the number of comparisons
+    pub fn par_comparisons(&self) -> usize {
+        *self.counter.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::setup;
+    use crate::server::test::TEST_PARAM;
+    use crate::{KnnError, SelectionNetwork};
+
+    #[test]
+    fn test_rank_check() {
+        assert!(SelectionNetwork::Rank.check(32, TEST_PARAM).is_ok());
+        assert!(matches!(
+            SelectionNetwork::Rank.check(100, TEST_PARAM),
+            Err(KnnError::RankTooLong {
+                n_rows: 100,
+                message_modulus: 32
+            })
+        ));
+        assert!(SelectionNetwork::Batcher.check(100, TEST_PARAM).is_ok());
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let (client, server) = setup(TEST_PARAM);
+        let server = Arc::new(RwLock::new(server));
+
+        // there are ties in the values to check the ordering by index
+        let pt_vec = vec![(5u64, 0u64), (2, 1), (7, 2), (2, 3), (0, 4), (9, 5)];
+        let k = 3;
+        let ct_vec: Vec<_> = pt_vec
+            .iter()
+            .map(|(v, c)| {
+                Arc::new(Mutex::new(EncItem::new(
+                    client.key.encrypt(*v),
+                    client.key.encrypt(*c),
+                )))
+            })
+            .collect();
+
+        let selector = RankSelect::par_new_k(k, server, TEST_PARAM);
+        selector.par_select(&ct_vec);
+        assert_eq!(
+            selector.par_comparisons(),
+            pt_vec.len() * (pt_vec.len() - 1) / 2
+        );
+
+        let actual: Vec<_> = ct_vec[..k]
+            .iter()
+            .map(|ct| ct.lock().unwrap().decrypt(&client.key))
+            .collect();
+        assert_eq!(actual, vec![(0, 4), (2, 1), (2, 3)]);
+    }
+}
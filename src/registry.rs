@@ -0,0 +1,388 @@
+use crate::message::{Query, QueryResult};
+use crate::protocol::answer;
+use crate::{
+    DistanceMode, EvaluationKeys, KnnError, KnnServer, Model, SelectionNetwork, ValueRange,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// A model in the registry together with the settings that are used to query it.
+#[derive(Clone, Debug)]
+pub struct ModelConfig {
+    pub data: Vec<Vec<u64>>,
+    pub labels: Vec<u64>,
+    /// The default `k`, used when the query does not set it.
+    pub k: usize,
+    pub network: SelectionNetwork,
+    pub distance_mode: DistanceMode,
+    pub distance_blocks: usize,
+    /// The range of the query features, see `KnnServer::set_query_range`.
+    pub query_range: Option<ValueRange>,
+}
+
+impl ModelConfig {
+    /// Create a model with the Batcher network and the plain distance mode.
+    pub fn new(data: Vec<Vec<u64>>, labels: Vec<u64>, k: usize) -> Self {
+        Self {
+            data,
+            labels,
+            k,
+            network: SelectionNetwork::Batcher,
+            distance_mode: DistanceMode::Plain,
+            distance_blocks: 1,
+            query_range: None,
+        }
+    }
+
+    /// Create a model from a model file, see `KnnServer::load_model`.
+    pub fn from_model(model: Model, k: usize) -> Self {
+        Self::new(model.rows, model.labels, k)
+    }
+
+    /// Check that the model fits the parameters of `server` without modifying it.
+    fn check(&self, server: &KnnServer) -> Result<(), KnnError> {
+        if self.data.len() != self.labels.len() {
+            return Err(KnnError::LabelCount {
+                rows: self.data.len(),
+                labels: self.labels.len(),
+            });
+        }
+        self.network.check(self.data.len(), server.params())?;
+        server.check_distance_blocks(self.distance_blocks)?;
+        let gamma = self.data.iter().fold(0usize, |acc, x| acc.max(x.len()));
+        server.check_data_in_range(&self.data, gamma, self.query_range)
+    }
+
+    fn apply(&self, server: &mut KnnServer) -> Result<(), KnnError> {
+        self.check(server)?;
+        server.set_query_range(self.query_range);
+        server.set_data(&self.data)?;
+        server.set_labels(&self.labels)?;
+        server.set_distance_mode(self.distance_mode);
+        server.set_distance_blocks(self.distance_blocks)?;
+        Ok(())
+    }
+}
+
+pub type SessionId = u64;
+
+struct Session {
+    keys: EvaluationKeys,
+    // one server per model, created on the first query to that model
+    servers: Mutex<HashMap<String, Arc<RwLock<KnnServer>>>>,
+}
+
+/// A collection of named models that are shared by many clients.
+/// Every client opens a session with its own evaluation keys,
+/// the queries are routed to a model by its name.
+/// Models can be loaded or replaced at any time,
+/// the sessions that already use the model see the new version from the next query.
+///
+/// The locks are always taken in the order models, sessions, servers of a session
+/// and a `KnnServer`, so a reload waits for the queries that are running on that model.
+#[derive(Default)]
+pub struct Registry {
+    models: RwLock<HashMap<String, Arc<ModelConfig>>>,
+    sessions: RwLock<HashMap<SessionId, Arc<Session>>>,
+    next_session: AtomicU64,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the model `name` or replace it if it already exists.
+    /// The model is checked against the keys of every session that uses it,
+    /// nothing is replaced if one of them returns an error.
+    pub fn load_model(&self, name: &str, config: ModelConfig) -> Result<(), KnnError> {
+        let mut models = self.models.write().unwrap();
+        let sessions = self.sessions.read().unwrap();
+        let servers: Vec<_> = sessions
+            .values()
+            .filter_map(|session| session.servers.lock().unwrap().get(name).cloned())
+            .collect();
+        for server in &servers {
+            config.check(&server.read().unwrap())?;
+        }
+        for server in &servers {
+            config.apply(&mut server.write().unwrap())?;
+        }
+        models.insert(name.to_string(), Arc::new(config));
+        Ok(())
+    }
+
+    /// Remove the model `name`, returns false if it does not exist.
+    pub fn remove_model(&self, name: &str) -> bool {
+        let mut models = self.models.write().unwrap();
+        for session in self.sessions.read().unwrap().values() {
+            session.servers.lock().unwrap().remove(name);
+        }
+        models.remove(name).is_some()
+    }
+
+    /// The names of all the models in lexicographic order.
+    pub fn model_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.models.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Start a session for a client, the keys are copied into
+    /// one `KnnServer` for every model that the session queries.
+    /// The keys are checked first since they come from the client.
+    pub fn open_session(&self, keys: EvaluationKeys) -> Result<SessionId, KnnError> {
+        keys.dist_modulus()?;
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+        let session = Session {
+            keys,
+            servers: Mutex::new(HashMap::new()),
+        };
+        self.sessions.write().unwrap().insert(id, Arc::new(session));
+        Ok(id)
+    }
+
+    /// End a session and drop its servers, returns false if it does not exist.
+    pub fn close_session(&self, id: SessionId) -> bool {
+        self.sessions.write().unwrap().remove(&id).is_some()
+    }
+
+    fn server(
+        &self,
+        id: SessionId,
+        name: &str,
+    ) -> io::Result<(Arc<RwLock<KnnServer>>, Arc<ModelConfig>)> {
+        let models = self.models.read().unwrap();
+        let config = models.get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown model {name}"))
+        })?;
+        let session = self
+            .sessions
+            .read()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("unknown session {id}"))
+            })?;
+
+        let mut servers = session.servers.lock().unwrap();
+        let server = match servers.get(name) {
+            Some(server) => server.clone(),
+            None => {
+                let mut server = KnnServer::from_evaluation_keys(session.keys.clone())?;
+                config.apply(&mut server)?;
+                let server = Arc::new(RwLock::new(server));
+                servers.insert(name.to_string(), server.clone());
+                server
+            }
+        };
+        Ok((server, config))
+    }
+
+    /// Run `query` on the model `query.model` with the keys of the session `id`.
+    pub fn answer(&self, id: SessionId, query: &Query) -> io::Result<QueryResult> {
+        let (server, config) = self.server(id, &query.model)?;
+        let k = if query.k == 0 { config.k } else { query.k };
+        answer(&server, k, config.network, query)
+    }
+}
+
+/// Keeps the models of a `Registry` in sync with their model files,
+/// see `KnnServer::save_model`.
+/// Every call to `poll` reloads the files whose modification time changed.
+pub struct ModelWatcher<F> {
+    files: Vec<(String, PathBuf, Option<SystemTime>)>,
+    config: F,
+}
+
+impl<F: Fn(Model) -> ModelConfig> ModelWatcher<F> {
+    /// Watch the model files in `files`, every one is loaded under its name
+    /// with the settings that `config` creates from the model.
+    pub fn new(files: Vec<(String, PathBuf)>, config: F) -> Self {
+        Self {
+            files: files
+                .into_iter()
+                .map(|(name, path)| (name, path, None))
+                .collect(),
+            config,
+        }
+    }
+
+    /// Load the files that changed since the last call into `registry`,
+    /// all the files are loaded on the first call.
+    /// The output has the name and the result of every model that was loaded.
+    /// A model that fails keeps its previous version until its file changes again,
+    /// except for a file that cannot be found, which is reported on every call.
+    pub fn poll(&mut self, registry: &Registry) -> Vec<(String, Result<(), KnnError>)> {
+        let mut out = vec![];
+        for (name, path, modified) in self.files.iter_mut() {
+            let result = fs::metadata(&path).and_then(|m| m.modified());
+            let time = match result {
+                Ok(time) => time,
+                Err(e) => {
+                    out.push((name.clone(), Err(KnnError::ModelFile(e))));
+                    continue;
+                }
+            };
+            if *modified == Some(time) {
+                continue;
+            }
+            *modified = Some(time);
+            let result = Model::load_file(&path)
+                .and_then(|model| registry.load_model(name, (self.config)(model)));
+            out.push((name.clone(), result));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{Message, QueryOutput};
+    use crate::server::test::TEST_PARAM;
+    use crate::{KnnClient, Quantization};
+
+    #[test]
+    fn test_registry() {
+        let (mut client, keys) = KnnClient::keygen(TEST_PARAM, 32);
+        let client_key = client.key.clone();
+        let data = vec![
+            vec![0, 0, 0, 0u64],
+            vec![1, 0, 0, 0],
+            vec![2, 0, 0, 0],
+            vec![3, 0, 0, 0],
+        ];
+        let registry = Registry::new();
+        registry
+            .load_model("a", ModelConfig::new(data.clone(), vec![1, 1, 2, 2], 1))
+            .unwrap();
+        registry
+            .load_model(
+                "b",
+                ModelConfig {
+                    network: SelectionNetwork::Rank,
+                    ..ModelConfig::new(data.clone(), vec![3, 3, 3, 0], 3)
+                },
+            )
+            .unwrap();
+        assert_eq!(registry.model_names(), vec!["a", "b"]);
+
+        let session = registry.open_session(keys).unwrap();
+        let mut query = |model: &str, output| {
+            let (glwe, lwe) = client.make_query(&[0, 0, 0, 0]);
+            Query {
+                glwe,
+                lwe,
+                k: 0,
+                output,
+                model: model.to_string(),
+            }
+        };
+        let nearest = |result: QueryResult| match result {
+            QueryResult::Nearest { items, .. } => items,
+            _ => panic!("expected the nearest items"),
+        };
+
+        // the default k of the model is used
+        let items = nearest(
+            registry
+                .answer(session, &query("a", QueryOutput::Nearest))
+                .unwrap(),
+        );
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].decrypt(&client_key), (0, 1));
+
+        match registry
+            .answer(session, &query("b", QueryOutput::Majority))
+            .unwrap()
+        {
+            QueryResult::Majority(ct) => assert_eq!(client_key.decrypt(&ct), 3),
+            _ => panic!("expected the majority class"),
+        }
+
+        // the existing server of the session is reloaded
+        registry
+            .load_model("a", ModelConfig::new(data, vec![2, 1, 2, 2], 1))
+            .unwrap();
+
+        // a model that does not fit the keys of the session is rejected
+        let too_far = ModelConfig::new(vec![vec![6, 0, 0, 0]], vec![0], 1);
+        assert!(registry.load_model("a", too_far).is_err());
+        let items = nearest(
+            registry
+                .answer(session, &query("a", QueryOutput::Nearest))
+                .unwrap(),
+        );
+        assert_eq!(items[0].decrypt(&client_key), (0, 2));
+
+        assert!(registry.remove_model("b"));
+        assert!(registry
+            .answer(session, &query("b", QueryOutput::Nearest))
+            .is_err());
+        assert!(registry.close_session(session));
+        assert!(registry
+            .answer(session, &query("a", QueryOutput::Nearest))
+            .is_err());
+    }
+
+    #[test]
+    fn test_watcher() {
+        let path = std::env::temp_dir().join(format!("ppknn-watch-{}.bin", std::process::id()));
+        let model = Model::new(vec![vec![0, 1], vec![1, 0]], vec![0, 1], Quantization::None);
+        model.save(&path).unwrap();
+
+        let registry = Registry::new();
+        let mut watcher = ModelWatcher::new(vec![("a".to_string(), path.clone())], |model| {
+            ModelConfig::from_model(model, 1)
+        });
+        let loaded = watcher.poll(&registry);
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].1.is_ok());
+        assert_eq!(registry.model_names(), vec!["a"]);
+        // the file did not change
+        assert!(watcher.poll(&registry).is_empty());
+
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(watcher.poll(&registry).len(), 1);
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            watcher.poll(&registry)[0].1,
+            Err(KnnError::ModelFile(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_config() {
+        let (_, keys) = KnnClient::keygen(TEST_PARAM, 32);
+        let server = KnnServer::from_evaluation_keys(keys).unwrap();
+        let config = ModelConfig::new(vec![vec![0, 1], vec![1, 0]], vec![0, 1], 1);
+        assert!(config.check(&server).is_ok());
+        // the distance modulus is the message modulus so there is only one block
+        let blocks = ModelConfig {
+            distance_blocks: 2,
+            ..config.clone()
+        };
+        assert!(matches!(
+            blocks.check(&server),
+            Err(KnnError::DistanceBlocks { blocks: 2, .. })
+        ));
+        let query_range = ModelConfig {
+            query_range: Some(ValueRange::new(0, 7)),
+            ..config
+        };
+        assert!(matches!(
+            query_range.check(&server),
+            Err(KnnError::DistanceOverflow { .. })
+        ));
+    }
+}
@@ -1,8 +1,19 @@
-use crate::client::KnnClient;
-use crate::EncItem;
+use crate::client::{EvaluationKeys, KnnClient};
+use crate::message::Message;
+use crate::{
+    preset, AsyncEncComparator, BatcherSort, DistanceAnalysis, EncItem, Feature, KnnError, Model,
+    Quantization, RankSelect, ValueRange,
+};
 use dyn_stack::{DynStack, GlobalMemBuffer, ReborrowMut};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use tfhe::core_crypto::algorithms::*;
 use tfhe::core_crypto::fft_impl::c64;
+use tfhe::core_crypto::fft_impl::crypto::ggsw::{cmux, cmux_scratch};
 use tfhe::core_crypto::fft_impl::math::fft::FftView;
 use tfhe::core_crypto::fft_impl::math::polynomial::FourierPolynomial;
 use tfhe::core_crypto::prelude::polynomial_algorithms::*;
@@ -10,7 +21,7 @@ use tfhe::core_crypto::prelude::slice_algorithms::*;
 use tfhe::core_crypto::prelude::*;
 use tfhe::shortint::ciphertext::Degree;
 use tfhe::shortint::server_key::Accumulator;
-use tfhe::shortint::{gen_keys, Ciphertext, Parameters, ServerKey};
+use tfhe::shortint::{Ciphertext, Parameters, ServerKey};
 
 pub(crate) fn polynomial_fft_wrapping_mul<Scalar, OutputCont, LhsCont, RhsCont>(
     output: &mut Polynomial<OutputCont>,
@@ -25,38 +36,86 @@ pub(crate) fn polynomial_fft_wrapping_mul<Scalar, OutputCont, LhsCont, RhsCont>(
     RhsCont: Container<Element = Scalar>,
 {
     assert_eq!(lhs.polynomial_size(), rhs.polynomial_size());
-    let n = lhs.polynomial_size().0;
 
-    let mut fourier_lhs = FourierPolynomial {
-        data: vec![c64::default(); n / 2],
-    };
-    let mut fourier_rhs = FourierPolynomial {
-        data: vec![c64::default(); n / 2],
-    };
+    let fourier_lhs = polynomial_fft_forward_torus(lhs, fft, stack);
+    let fourier_rhs = polynomial_fft_forward_integer(rhs, fft, stack);
+    fourier_polynomial_wrapping_mul(output, &fourier_lhs, &fourier_rhs, fft, stack);
+}
 
+/// Compute the Fourier transform of a polynomial with torus coefficients.
+pub(crate) fn polynomial_fft_forward_torus<Scalar, Cont>(
+    poly: &Polynomial<Cont>,
+    fft: FftView,
+    stack: &mut DynStack,
+) -> FourierPolynomial<Vec<c64>>
+where
+    Scalar: UnsignedTorus,
+    Cont: Container<Element = Scalar>,
+{
+    let mut fourier = FourierPolynomial {
+        data: vec![c64::default(); poly.polynomial_size().0 / 2],
+    };
     fft.forward_as_torus(
-        unsafe { fourier_lhs.as_mut_view().into_uninit() },
-        lhs.as_view(),
+        unsafe { fourier.as_mut_view().into_uninit() },
+        poly.as_view(),
         stack.rb_mut(),
     );
+    fourier
+}
+
+/// Compute the Fourier transform of a polynomial with integer coefficients.
+pub(crate) fn polynomial_fft_forward_integer<Scalar, Cont>(
+    poly: &Polynomial<Cont>,
+    fft: FftView,
+    stack: &mut DynStack,
+) -> FourierPolynomial<Vec<c64>>
+where
+    Scalar: UnsignedTorus,
+    Cont: Container<Element = Scalar>,
+{
+    let mut fourier = FourierPolynomial {
+        data: vec![c64::default(); poly.polynomial_size().0 / 2],
+    };
     fft.forward_as_integer(
-        unsafe { fourier_rhs.as_mut_view().into_uninit() },
-        rhs.as_view(),
+        unsafe { fourier.as_mut_view().into_uninit() },
+        poly.as_view(),
         stack.rb_mut(),
     );
+    fourier
+}
 
-    for (a, b) in fourier_lhs.data.iter_mut().zip(fourier_rhs.data.iter()) {
-        *a *= *b;
-    }
+/// Multiply two polynomials that are already in the Fourier domain,
+/// the left hand side is the torus polynomial,
+/// and write the product back to `output` in the standard domain.
+pub(crate) fn fourier_polynomial_wrapping_mul<Scalar, OutputCont>(
+    output: &mut Polynomial<OutputCont>,
+    lhs: &FourierPolynomial<Vec<c64>>,
+    rhs: &FourierPolynomial<Vec<c64>>,
+    fft: FftView,
+    stack: &mut DynStack,
+) where
+    Scalar: UnsignedTorus,
+    OutputCont: ContainerMut<Element = Scalar>,
+{
+    assert_eq!(lhs.data.len(), rhs.data.len());
+    let product = FourierPolynomial {
+        data: lhs
+            .data
+            .iter()
+            .zip(rhs.data.iter())
+            .map(|(a, b)| *a * *b)
+            .collect::<Vec<_>>(),
+    };
 
     fft.backward_as_torus(
         unsafe { output.as_mut_view().into_uninit() },
-        fourier_lhs.as_view(),
+        product.as_view(),
         stack.rb_mut(),
     );
 }
 
-pub(crate) fn setup_polymul_fft(params: Parameters) -> (Fft, GlobalMemBuffer) {
+/// Create a new FFT context and the scratch memory for polynomial multiplication.
+pub fn setup_polymul_fft(params: Parameters) -> (Fft, GlobalMemBuffer) {
     let fft = Fft::new(params.polynomial_size);
     let fft_view = fft.as_view();
 
@@ -69,6 +128,154 @@ pub(crate) fn setup_polymul_fft(params: Parameters) -> (Fft, GlobalMemBuffer) {
     (fft, mem)
 }
 
+fn analysis_in_range(rows: &[Vec<u64>], query_range: Option<ValueRange>) -> DistanceAnalysis {
+    match query_range {
+        Some(range) => DistanceAnalysis::new(rows, range),
+        None => DistanceAnalysis::in_model_range(rows),
+    }
+}
+
+/// Switch a torus element to the modulus `2N` with rounding,
+/// this matches the modulus switch used by the bootstrap in tfhe.
+fn pbs_modulus_switch(input: u64, polynomial_size: PolynomialSize) -> usize {
+    let log_2n = polynomial_size.0.ilog2() + 1;
+    let mut output = input >> (u64::BITS - log_2n - 1);
+    output += output & 1;
+    (output >> 1) as usize
+}
+
+/// The largest base `b` such that `b^(blocks - 1) <= ratio`,
+/// i.e., the radix base of `set_distance_blocks` when the ratio is an exact power.
+/// There must be at least two blocks, one block has no base.
+fn radix_base(ratio: u64, blocks: usize) -> u64 {
+    assert!(blocks >= 2, "the radix base needs at least two blocks");
+    let exp = blocks as u32 - 1;
+    let fits = |base: u64| base.checked_pow(exp).is_some_and(|x| x <= ratio);
+    // start from the floating point root and correct its rounding error
+    let mut base = ((ratio as f64).powf(1.0 / exp as f64) as u64).max(1);
+    while base > 1 && !fits(base) {
+        base -= 1;
+    }
+    while fits(base + 1) {
+        base += 1;
+    }
+    base
+}
+
+thread_local! {
+    // FFT plans and scratch buffers owned by the current thread,
+    // keyed by the polynomial size
+    static FFT_POOL: RefCell<HashMap<usize, (Fft, GlobalMemBuffer)>> = RefCell::new(HashMap::new());
+}
+
+/// Run `f` with an FFT context taken from the thread-local pool,
+/// the context is created on first use and reused by later calls on the same thread.
+/// Nested calls do not share the context, the inner call allocates its own.
+pub(crate) fn with_polymul_fft<R>(
+    params: Parameters,
+    f: impl FnOnce(FftView, &mut DynStack) -> R,
+) -> R {
+    let n = params.polynomial_size.0;
+    let (fft, mut mem) = FFT_POOL
+        .with(|pool| pool.borrow_mut().remove(&n))
+        .unwrap_or_else(|| setup_polymul_fft(params));
+
+    let out = {
+        let mut stack = DynStack::new(&mut mem);
+        f(fft.as_view(), &mut stack)
+    };
+
+    FFT_POOL.with(|pool| pool.borrow_mut().insert(n, (fft, mem)));
+    out
+}
+
+/// The layout of the model rows used for computing the distances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceMode {
+    /// Every model row is in its own polynomial,
+    /// so there is one polynomial multiplication per row.
+    Plain,
+    /// Up to `N / gamma` model rows are laid out in one polynomial at offsets
+    /// that are multiples of `gamma`, every distance is sample extracted at a different degree.
+    /// This cuts the number of polynomial multiplications by roughly `N / gamma`.
+    ///
+    /// Every extracted coefficient picks up the GLWE noise of the query times all the
+    /// coefficients of its packing, not only the ones of its row. The noise variance of a
+    /// distance is `σ_lwe^2 + 4 σ_glwe^2 Σ_r |m_r|^2`, summed over the rows in the same packing,
+    /// instead of `σ_lwe^2 + 4 σ_glwe^2 |m_r|^2` in `Plain`, i.e., at most
+    /// `σ_lwe^2 + 4 N max^2 σ_glwe^2` for features up to `max`.
+    /// It is safe when this stays far below `(dist_delta / 2)^2`, which holds as long as
+    /// `4 N max^2 σ_glwe^2` is small next to `σ_lwe^2`, as for the built-in presets.
+    Packed,
+}
+
+/// The network that selects the `k` nearest items.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionNetwork {
+    /// The truncated Batcher sorting network.
+    Batcher,
+    /// Rank based selection with depth one, see `RankSelect`,
+    /// it does not support radix distances.
+    Rank,
+}
+
+impl SelectionNetwork {
+    /// Check that the network can select from `n_rows` items with `params`,
+    /// the ranks of `Rank` must fit in the message modulus.
+    pub fn check(&self, n_rows: usize, params: Parameters) -> Result<(), KnnError> {
+        match self {
+            SelectionNetwork::Rank if n_rows > params.message_modulus.0 => {
+                Err(KnnError::RankTooLong {
+                    n_rows,
+                    message_modulus: params.message_modulus.0,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The contribution of every neighbour to the weighted vote,
+/// it is a function of the lowered distance and evaluated with PBS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoteWeight {
+    /// Every neighbour has weight 1, which is the plain majority.
+    Uniform,
+    /// The weight is `ceil(max_weight / (d + 1))`, so closer neighbours count more.
+    InverseDistance,
+    /// The weight is `max_weight` if the distance is smaller than the threshold and 1 otherwise.
+    Step(u64),
+}
+
+impl VoteWeight {
+    /// Evaluate the weight of a neighbour at distance `d`,
+    /// the output is always between 1 and `max_weight`.
+    pub fn weight(&self, d: u64, max_weight: u64) -> u64 {
+        match self {
+            VoteWeight::Uniform => 1,
+            VoteWeight::InverseDistance => (max_weight + d) / (d + 1),
+            VoteWeight::Step(threshold) => {
+                if d < *threshold {
+                    max_weight
+                } else {
+                    1
+                }
+            }
+        }
+    }
+}
+
+/// How the mean of the k-NN regression is computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegressionOutput {
+    /// The server outputs the sum of the targets
+    /// and the client divides it by `k`, so the mean is exact.
+    ExactMean,
+    /// The server outputs the mean of the targets rounded to the nearest integer,
+    /// computed with PBS.
+    RoundedMean,
+}
+
 /// This structure represents the server that is executing
 /// privacy preserving k-NN. It needs to be constructed
 /// using the `setup` function (or other variations such as `setup_with_modulus`).
@@ -79,12 +286,61 @@ pub struct KnnServer {
     dist_delta: u64, // delta value for distance computation
     gamma: usize,
     data: Vec<PlaintextListOwned<u64>>,
-    labels: Vec<Ciphertext>, // trivially encrypted labels
+    fourier_data: Vec<FourierPolynomial<Vec<c64>>>, // the model rows in the Fourier domain
+    norms: Vec<u64>,                                // \sum_{i=0}^{\gamma-1} m_i^2 for every row
+    packed_data: Vec<FourierPolynomial<Vec<c64>>>, // many rows per polynomial in the Fourier domain
+    distance_mode: DistanceMode,
+    distance_blocks: usize,
+    labels: Vec<Vec<Ciphertext>>, // trivially encrypted labels, one ciphertext per block
+    label_blocks: usize,
+    extra_payload: Vec<Vec<Ciphertext>>, // trivially encrypted payload after the label of every row
+    n_classes: usize,
+    rows: Vec<Vec<u64>>,    // the clear model rows, kept for `save_model`
+    clear_labels: Vec<u64>, // the clear labels, kept for `save_model`
+    quantization: Quantization,
+    features: Vec<Feature>,
+    query_range: Option<ValueRange>,
 }
 
 impl KnnServer {
+    /// Create a server from the public keys generated by `KnnClient::keygen`,
+    /// the model needs to be set with `set_data` and `set_labels` afterwards.
+    /// The keys are checked with `EvaluationKeys::dist_modulus` since they come from the client.
+    pub fn from_evaluation_keys(keys: EvaluationKeys) -> Result<Self, KnnError> {
+        keys.dist_modulus()?;
+        let EvaluationKeys {
+            server_key,
+            lwe_to_glwe_ksk,
+            dist_delta,
+            params,
+        } = keys;
+        Ok(Self {
+            key: server_key,
+            lwe_to_glwe_ksk,
+            params,
+            dist_delta,
+            gamma: 0,
+            data: vec![],
+            fourier_data: vec![],
+            norms: vec![],
+            packed_data: vec![],
+            distance_mode: DistanceMode::Plain,
+            distance_blocks: 1,
+            labels: vec![],
+            label_blocks: 1,
+            extra_payload: vec![],
+            n_classes: 0,
+            rows: vec![],
+            clear_labels: vec![],
+            quantization: Quantization::None,
+            features: vec![],
+            query_range: None,
+        })
+    }
+
     /// Compute the squared distances between the target vector given by `c` and `c2`
     /// with the model stored in the server.
+    /// The rows are processed in parallel, every thread uses its own FFT context.
     /// The precision is reduced automatically if the distance plaintext modulus
     /// does not match with the sorting plaintext modulus.
     pub fn compute_distances(
@@ -92,9 +348,47 @@ impl KnnServer {
         c: &GlweCiphertextOwned<u64>,
         c2: &Ciphertext,
     ) -> Vec<Ciphertext> {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let mut stack = DynStack::new(&mut mem);
-        self.compute_distances_with_fft(c, c2, fft.as_view(), &mut stack)
+        let mut distances = self.compute_raw_distances(c, c2);
+        if self.dist_delta != self.delta() {
+            // the recentering shift is already added in `extract_distance`
+            distances
+                .par_iter_mut()
+                .for_each(|x| self.key.keyswitch_bootstrap_assign(x));
+        }
+        distances
+    }
+
+    /// Compute the squared distances in parallel without reducing the precision,
+    /// the recentering shift is included if the precision needs to be reduced.
+    fn compute_raw_distances(
+        &self,
+        c: &GlweCiphertextOwned<u64>,
+        c2: &Ciphertext,
+    ) -> Vec<Ciphertext> {
+        let query = with_polymul_fft(self.params, |fft, stack| self.fourier_query(c, fft, stack));
+        match self.distance_mode {
+            DistanceMode::Plain => self
+                .fourier_data
+                .par_iter()
+                .zip(self.norms.par_iter())
+                .map(|(m, m2)| {
+                    with_polymul_fft(self.params, |fft, stack| {
+                        let glwe = self.fourier_glwe_mul(&query, m, fft, stack);
+                        self.extract_distance(&glwe, self.gamma - 1, c2, *m2)
+                    })
+                })
+                .collect(),
+            DistanceMode::Packed => self
+                .packed_data
+                .par_iter()
+                .enumerate()
+                .flat_map_iter(|(p, packed)| {
+                    with_polymul_fft(self.params, |fft, stack| {
+                        self.packed_distances(&query, c2, p, packed, fft, stack)
+                    })
+                })
+                .collect(),
+        }
     }
 
     /// Compute the squared distances between the target vector given by `c` and `c2`
@@ -108,68 +402,241 @@ impl KnnServer {
         fft: FftView,
         stack: &mut DynStack,
     ) -> Vec<Ciphertext> {
-        let delta = self.dist_delta;
-        let mut distances: Vec<_> = self
-            .data
-            .iter()
-            .map(|m| {
-                let mut glwe = c.clone();
-                // we want to compute c^2 - 2 * m * c + m^2
-                // first compute m*c where c is a RLWE
-                glwe.get_mut_mask()
-                    .as_mut_polynomial_list()
-                    .iter_mut()
-                    .for_each(|mut mask| {
-                        polynomial_fft_wrapping_mul(
-                            &mut mask,
-                            &c.get_mask().as_polynomial_list().get(0),
-                            &m.as_polynomial(),
-                            fft,
-                            stack,
-                        );
-                    });
-                polynomial_fft_wrapping_mul(
-                    &mut glwe.get_mut_body().as_mut_polynomial(),
-                    &c.get_body().as_polynomial(),
-                    &m.as_polynomial(),
-                    fft,
-                    stack,
-                );
+        let query = self.fourier_query(c, fft, stack);
+        let mut distances: Vec<_> = match self.distance_mode {
+            DistanceMode::Plain => self
+                .fourier_data
+                .iter()
+                .zip(self.norms.iter())
+                .map(|(m, m2)| {
+                    let glwe = self.fourier_glwe_mul(&query, m, fft, stack);
+                    self.extract_distance(&glwe, self.gamma - 1, c2, *m2)
+                })
+                .collect(),
+            DistanceMode::Packed => self
+                .packed_data
+                .iter()
+                .enumerate()
+                .flat_map(|(p, packed)| self.packed_distances(&query, c2, p, packed, fft, stack))
+                .collect(),
+        };
 
-                // sample extract the \gamma -1 th coeff
-                // m_times_c = m*c
-                let m_times_c = {
-                    let mut lwe = self.new_ct();
-                    extract_lwe_sample_from_glwe_ciphertext(
-                        &glwe,
-                        &mut lwe.ct,
-                        MonomialDegree(self.gamma - 1),
-                    );
-                    lwe
-                };
-
-                // c2 = \sum_{i=0}^{\gamma-1} c_i^2
-                // out <- out - m_times_c * 2
-                let mut out = c2.clone();
-                slice_wrapping_sub_scalar_mul_assign(out.ct.as_mut(), m_times_c.ct.as_ref(), 2);
-
-                // add \sum_{i=0}^{\gamma-1} m_i^2
-                // NOTE: m2 can be pre-computed, but we won't save much
-                // since distance computation is fast anyway
-                let m2 = Plaintext(delta * m.iter().map(|x| *x.0 * *x.0).sum::<u64>());
-                lwe_ciphertext_plaintext_add_assign(&mut out.ct, m2);
-                out
+        if self.dist_delta != self.delta() {
+            // the recentering shift is already added in `extract_distance`
+            distances
+                .iter_mut()
+                .for_each(|x| self.key.keyswitch_bootstrap_assign(x));
+        }
+        distances
+    }
+
+    /// Select how the model rows are multiplied with the query,
+    /// see `DistanceMode` for the options.
+    pub fn set_distance_mode(&mut self, mode: DistanceMode) {
+        self.distance_mode = mode;
+    }
+
+    /// The number of model rows that fit in one polynomial when using `DistanceMode::Packed`.
+    pub fn rows_per_packing(&self) -> usize {
+        if self.gamma == 0 {
+            0
+        } else {
+            self.params.polynomial_size.0 / self.gamma
+        }
+    }
+
+    /// Keep every distance as `blocks` radix blocks instead of one ciphertext,
+    /// the comparators then compare the blocks lexicographically.
+    /// The ratio between the distance plaintext modulus and the sorting plaintext modulus
+    /// must be `base^(blocks - 1)` where the base is at most a quarter of the message modulus.
+    /// Every extra block costs more bootstraps in the distance computation and in every comparison,
+    /// and the noise of the lower blocks grows by a factor of the base per block.
+    /// See `check_distance_blocks` for the errors.
+    pub fn set_distance_blocks(&mut self, blocks: usize) -> Result<(), KnnError> {
+        self.check_distance_blocks(blocks)?;
+        self.distance_blocks = blocks;
+        Ok(())
+    }
+
+    /// Check that the distances can be split into `blocks` radix blocks,
+    /// see `set_distance_blocks`.
+    pub fn check_distance_blocks(&self, blocks: usize) -> Result<(), KnnError> {
+        let ratio = self.delta() / self.dist_delta;
+        let message_modulus = self.params.message_modulus.0;
+        let valid = match blocks {
+            0 => false,
+            1 => true,
+            _ => {
+                let base = radix_base(ratio, blocks);
+                base.pow(blocks as u32 - 1) == ratio
+                    && base >= 2
+                    && base <= message_modulus as u64 / 4
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(KnnError::DistanceBlocks {
+                blocks,
+                ratio,
+                message_modulus,
             })
-            .collect();
+        }
+    }
+
+    /// The number of radix blocks of every distance, see `set_distance_blocks`.
+    pub fn distance_blocks(&self) -> usize {
+        self.distance_blocks
+    }
+
+    /// Split a distance that is encoded with `dist_delta` into `distance_blocks` blocks,
+    /// the most significant first, and every block is encoded with `delta`.
+    /// The input must contain the recentering shift from `extract_distance`.
+    //
+    // NOTE: the top block is rounded by the bootstrap in the same way as `lower_precision`,
+    // so it can be off by one when the distance is close to a block boundary.
+    // The remainder is computed linearly from the top block, so it absorbs the error
+    // and may be slightly negative or slightly larger than the block size.
+    // We add an offset to every lower block to keep them in the lower half of the plaintext space,
+    // the order can only change between distances that are close to each other.
+    fn radix_decompose(&self, d: &Ciphertext) -> Vec<Ciphertext> {
+        let blocks = self.distance_blocks;
+        let ratio = self.delta() / self.dist_delta;
+        let base = radix_base(ratio, blocks);
+        let offset = (self.params.message_modulus.0 as u64 / 2 - base) / 2;
+
+        let mut top = d.clone();
+        self.key.keyswitch_bootstrap_assign(&mut top);
+        let mut out = vec![top.clone()];
+
+        // the remainder `d - top * ratio` in units of `dist_delta`,
+        // the recentering shift is removed and the offset is added
+        let mut rem = self.raw_sub(d, &top);
+        let constant = (offset * self.dist_delta).wrapping_sub(self.recentering_shift().0);
+        lwe_ciphertext_plaintext_add_assign(&mut rem.ct, Plaintext(constant));
+
+        let mut unit = self.dist_delta;
+        for _ in 1..blocks - 1 {
+            // move the next digit to the top so that the bootstrap can extract it
+            slice_wrapping_scalar_mul_assign(rem.ct.as_mut(), base);
+            unit *= base;
+
+            let mut digit = rem.clone();
+            let shift = ((self.delta() / unit - 1) * unit / 2).wrapping_neg();
+            lwe_ciphertext_plaintext_add_assign(&mut digit.ct, Plaintext(shift));
+            self.key.keyswitch_bootstrap_assign(&mut digit);
+
+            self.raw_sub_assign(&mut rem, &digit);
+            lwe_ciphertext_plaintext_add_assign(&mut rem.ct, Plaintext(offset * unit));
+            out.push(digit);
+        }
+
+        // the last block is already small, only its scale needs to change
+        slice_wrapping_scalar_mul_assign(rem.ct.as_mut(), self.delta() / unit);
+        out.push(rem);
+        out
+    }
+
+    /// Transform every polynomial (the mask and the body) of the query into the Fourier domain,
+    /// this only needs to be done once per query.
+    fn fourier_query(
+        &self,
+        c: &GlweCiphertextOwned<u64>,
+        fft: FftView,
+        stack: &mut DynStack,
+    ) -> Vec<FourierPolynomial<Vec<c64>>> {
+        c.as_polynomial_list()
+            .iter()
+            .map(|poly| polynomial_fft_forward_torus(&poly, fft, stack))
+            .collect()
+    }
+
+    /// Multiply the query, given in the Fourier domain by `query`,
+    /// with the plaintext polynomial `m` that is also in the Fourier domain.
+    /// Only pointwise products are needed since both are in the Fourier domain.
+    fn fourier_glwe_mul(
+        &self,
+        query: &[FourierPolynomial<Vec<c64>>],
+        m: &FourierPolynomial<Vec<c64>>,
+        fft: FftView,
+        stack: &mut DynStack,
+    ) -> GlweCiphertextOwned<u64> {
+        let mut glwe = GlweCiphertext::new(
+            0u64,
+            self.params.glwe_dimension.to_glwe_size(),
+            self.params.polynomial_size,
+        );
+        glwe.as_mut_polynomial_list()
+            .iter_mut()
+            .zip(query.iter())
+            .for_each(|(mut out, q)| {
+                fourier_polynomial_wrapping_mul(&mut out, q, m, fft, stack);
+            });
+        glwe
+    }
+
+    /// Finish the distance computation from `glwe = m*c`
+    /// where the inner product between the model row and the query is at coefficient `degree`.
+    fn extract_distance(
+        &self,
+        glwe: &GlweCiphertextOwned<u64>,
+        degree: usize,
+        c2: &Ciphertext,
+        m2: u64,
+    ) -> Ciphertext {
+        // we want to compute c^2 - 2 * m * c + m^2
+        // sample extract the inner product
+        // m_times_c = m*c
+        let m_times_c = {
+            let mut lwe = self.new_ct();
+            extract_lwe_sample_from_glwe_ciphertext(glwe, &mut lwe.ct, MonomialDegree(degree));
+            lwe
+        };
 
+        // c2 = \sum_{i=0}^{\gamma-1} c_i^2
+        // out <- out - m_times_c * 2
+        let mut out = c2.clone();
+        slice_wrapping_sub_scalar_mul_assign(out.ct.as_mut(), m_times_c.ct.as_ref(), 2);
+
+        // add \sum_{i=0}^{\gamma-1} m_i^2 which is computed in `set_data`,
+        // together with the recentering shift if the precision is reduced later
+        let mut constant = self.dist_delta * m2;
         if self.dist_delta != self.delta() {
-            distances.iter_mut().for_each(|x| self.lower_precision(x));
+            constant = constant.wrapping_add(self.recentering_shift().0);
         }
-        distances
+        lwe_ciphertext_plaintext_add_assign(&mut out.ct, Plaintext(constant));
+        out
+    }
+
+    /// Compute the distances for all the rows in the `p`-th packed polynomial.
+    /// Row `r` in the packing starts at the coefficient `r * gamma`,
+    /// so its inner product with the query is at the coefficient `r * gamma + gamma - 1`.
+    fn packed_distances(
+        &self,
+        query: &[FourierPolynomial<Vec<c64>>],
+        c2: &Ciphertext,
+        p: usize,
+        packed: &FourierPolynomial<Vec<c64>>,
+        fft: FftView,
+        stack: &mut DynStack,
+    ) -> Vec<Ciphertext> {
+        let rows_per_packing = self.rows_per_packing();
+        let first_row = p * rows_per_packing;
+        let last_row = (first_row + rows_per_packing).min(self.norms.len());
+
+        let glwe = self.fourier_glwe_mul(query, packed, fft, stack);
+        (first_row..last_row)
+            .map(|row| {
+                let r = row - first_row;
+                self.extract_distance(&glwe, r * self.gamma + self.gamma - 1, c2, self.norms[row])
+            })
+            .collect()
     }
 
     /// Compute the squared distances between the target vector given by `c` and `c2`
     /// with the model stored in the server and zip the result with the existing labels/classes.
+    /// The payload of every item is the label followed by the extra payload of the row,
+    /// see `set_payload`.
     /// The precision is reduced automatically if the distance plaintext modulus
     /// does not match with the sorting plaintext modulus.
     pub fn compute_distances_with_labels(
@@ -177,17 +644,207 @@ impl KnnServer {
         c: &GlweCiphertextOwned<u64>,
         c2: &Ciphertext,
     ) -> Vec<EncItem> {
-        let distances = self.compute_distances(c, c2);
+        let distances: Vec<_> = if self.distance_blocks > 1 {
+            self.compute_raw_distances(c, c2)
+                .par_iter()
+                .map(|d| self.radix_decompose(d))
+                .collect()
+        } else {
+            self.compute_distances(c, c2)
+                .into_iter()
+                .map(|d| vec![d])
+                .collect()
+        };
         let enc_vec = distances
             .into_iter()
-            .zip(self.labels.iter())
-            .map(|(d, l)| EncItem::new(d, l.clone()))
+            .enumerate()
+            .map(|(row, blocks)| {
+                let mut payload = self.labels[row].clone();
+                // the payload is either unset or has one entry per row, see `set_payload`
+                if !self.extra_payload.is_empty() {
+                    payload.extend_from_slice(&self.extra_payload[row]);
+                }
+                EncItem::new_radix(blocks, payload)
+            })
             .collect::<Vec<_>>();
         enc_vec
     }
 
+    /// Set the payload that is carried with every model row after the label,
+    /// e.g., the regression target or other attributes of the record.
+    /// There must be one payload per model row, so it is set after `set_data`,
+    /// an empty `payload` removes it.
+    /// All the rows must have the same payload length, every value must be smaller
+    /// than the message modulus and every comparison needs one extra bootstrap
+    /// per payload ciphertext.
+    /// The payload is not modified if an error is returned.
+    pub fn set_payload(&mut self, payload: &[Vec<u64>]) -> Result<(), KnnError> {
+        if !payload.is_empty() && payload.len() != self.data.len() {
+            return Err(KnnError::PayloadCount {
+                rows: self.data.len(),
+                payloads: payload.len(),
+            });
+        }
+        let width = payload.first().map_or(0, |p| p.len());
+        if let Some((row, p)) = payload.iter().enumerate().find(|(_, p)| p.len() != width) {
+            return Err(KnnError::PayloadLength {
+                row,
+                len: p.len(),
+                width,
+            });
+        }
+        let message_modulus = self.params.message_modulus.0;
+        for (row, p) in payload.iter().enumerate() {
+            if let Some(value) = p.iter().find(|x| **x >= message_modulus as u64) {
+                return Err(KnnError::PayloadTooLarge {
+                    row,
+                    value: *value,
+                    message_modulus,
+                });
+            }
+        }
+
+        let delta = self.delta();
+        self.extra_payload = payload
+            .iter()
+            .map(|p| {
+                p.iter()
+                    .map(|x| self.trivially_encrypt_with_delta(*x, delta))
+                    .collect()
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// The number of payload ciphertexts in the items from `compute_distances_with_labels`,
+    /// this is the label blocks and the extra payload.
+    pub fn payload_len(&self) -> usize {
+        self.label_blocks + self.extra_payload.first().map_or(0, |p| p.len())
+    }
+
+    /// The number of rows in the model.
+    pub fn n_rows(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn params(&self) -> Parameters {
+        self.params
+    }
+
+    /// The number of ciphertexts needed to encrypt the record id of any model row,
+    /// every ciphertext holds one digit in base message modulus.
+    pub fn id_blocks(&self) -> usize {
+        self.radix_blocks(self.data.len())
+    }
+
+    /// Like `compute_distances_with_labels` but every item also carries
+    /// the trivially encrypted index of its row as the record id.
+    /// The id has `id_blocks` digits, the least significant first,
+    /// which are appended to the payload after the first `payload_len` ciphertexts.
+    /// The ids are only hidden after they go through the selection.
+    pub fn compute_distances_with_ids(
+        &self,
+        c: &GlweCiphertextOwned<u64>,
+        c2: &Ciphertext,
+    ) -> Vec<EncItem> {
+        let base = self.params.message_modulus.0 as u64;
+        let blocks = self.id_blocks();
+        self.compute_distances_with_labels(c, c2)
+            .into_iter()
+            .enumerate()
+            .map(|(row, mut item)| {
+                item.payload.extend(
+                    (0..blocks)
+                        .map(|b| self.trivially_encrypt((row as u64 / base.pow(b as u32)) % base)),
+                );
+                item
+            })
+            .collect()
+    }
+
+    /// Find the record ids of the `k` nearest rows to the target vector given by `c` and `c2`.
+    /// The items are sorted with the Batcher network so the ids are moved obliviously,
+    /// the output has one encrypted id per neighbour in ascending order of the distance,
+    /// use `KnnClient::decrypt_id` to decrypt them.
+    pub fn search(
+        server: &Arc<RwLock<KnnServer>>,
+        k: usize,
+        c: &GlweCiphertextOwned<u64>,
+        c2: &Ciphertext,
+    ) -> Vec<Vec<Ciphertext>> {
+        let (items, id_start) = {
+            let server = server.read().unwrap();
+            (
+                server.compute_distances_with_ids(c, c2),
+                server.payload_len(),
+            )
+        };
+        Self::select_k(server, k, SelectionNetwork::Batcher, items)
+            .into_iter()
+            .map(|mut item| item.payload.split_off(id_start))
+            .collect()
+    }
+
+    /// Find the `k` nearest items to the target vector given by `c` and `c2`
+    /// in ascending order of the distance,
+    /// every item carries the payload from `compute_distances_with_labels`.
+    pub fn nearest(
+        server: &Arc<RwLock<KnnServer>>,
+        k: usize,
+        network: SelectionNetwork,
+        c: &GlweCiphertextOwned<u64>,
+        c2: &Ciphertext,
+    ) -> Vec<EncItem> {
+        let items = server.read().unwrap().compute_distances_with_labels(c, c2);
+        Self::select_k(server, k, network, items)
+    }
+
+    fn select_k(
+        server: &Arc<RwLock<KnnServer>>,
+        k: usize,
+        network: SelectionNetwork,
+        items: Vec<EncItem>,
+    ) -> Vec<EncItem> {
+        let params = server.read().unwrap().params;
+        let items: Vec<_> = items
+            .into_iter()
+            .map(|item| Arc::new(Mutex::new(item)))
+            .collect();
+
+        match network {
+            SelectionNetwork::Batcher => {
+                let cmp = AsyncEncComparator::new(server.clone(), params);
+                let sorter = BatcherSort::par_new_k(k, cmp, false);
+                sorter.par_sort(&items);
+            }
+            SelectionNetwork::Rank => {
+                let selector = RankSelect::par_new_k(k, server.clone(), params);
+                selector.par_select(&items);
+            }
+        }
+
+        items[..k]
+            .iter()
+            .map(|item| item.lock().unwrap().clone())
+            .collect()
+    }
+
     /// Reduce the plaintext modulus in `ct`.
     pub fn lower_precision(&self, ct: &mut Ciphertext) {
+        lwe_ciphertext_plaintext_add_assign(&mut ct.ct, self.recentering_shift());
+        self.key.keyswitch_bootstrap_assign(ct)
+    }
+
+    /// The plaintext that is added to a distance before reducing its precision.
+    //
+    // NOTE: only this shift can be folded into other linear steps, e.g., the `m^2` term
+    // in `extract_distance`, but not the bootstrap itself.
+    // The comparator accumulators are built from the encrypted inputs
+    // (LWE to GLWE keyswitch multiplied by a 0/1 polynomial)
+    // so a bootstrap that uses them can only select one of the inputs, not round it.
+    // Feeding the high precision distances directly into the first comparator level
+    // would therefore output high precision values that still need this bootstrap.
+    fn recentering_shift(&self) -> Plaintext<u64> {
         // we assume the original ciphertext is encoded with higher precision
         // than the TFHE parameter
         // the number of elements that gets mapped into one element in the smaller message modulus
@@ -202,10 +859,7 @@ impl KnnServer {
         // the recentering is done by subtracting
         // half of the maximum value (precision_ratio-1)*Delta
         // from the original pt
-        let shift = Plaintext(((delta * (precision_ratio - 1)) / 2).wrapping_neg());
-        lwe_ciphertext_plaintext_add_assign(&mut ct.ct, shift);
-
-        self.key.keyswitch_bootstrap_assign(ct)
+        Plaintext(((delta * (precision_ratio - 1)) / 2).wrapping_neg())
     }
 
     /// Keyswitch from LWE to RLWE.
@@ -379,7 +1033,7 @@ impl KnnServer {
         self.double_glwe_acc(&left_glwe, &right_glwe, fft, stack)
     }
 
-    fn special_sub(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
+    pub(crate) fn special_sub(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
         // we use a raw subtract and then add by t/2 to ensure the negative
         // does not overflow into the padding bit
 
@@ -392,6 +1046,106 @@ impl KnnServer {
         res
     }
 
+    /// Execute `keyswitch_programmable_bootstrap` on every `(ct, acc)` pair in `inputs`.
+    /// Instead of running the blind rotations one after another,
+    /// every thread takes a chunk of the inputs and iterates over the bootstrapping key
+    /// in the outer loop, so that each GGSW ciphertext stays in the cache
+    /// while it is used by all the blind rotations in the chunk.
+    /// The outputs are in the same order as the inputs.
+    pub fn batch_keyswitch_programmable_bootstrap(
+        &self,
+        inputs: &[(Ciphertext, Accumulator)],
+    ) -> Vec<Ciphertext> {
+        if inputs.is_empty() {
+            return vec![];
+        }
+        let chunk_size =
+            (inputs.len() + rayon::current_num_threads() - 1) / rayon::current_num_threads();
+        inputs
+            .par_chunks(chunk_size)
+            .flat_map_iter(|chunk| self.batch_keyswitch_programmable_bootstrap_chunk(chunk))
+            .collect()
+    }
+
+    fn batch_keyswitch_programmable_bootstrap_chunk(
+        &self,
+        inputs: &[(Ciphertext, Accumulator)],
+    ) -> Vec<Ciphertext> {
+        let polynomial_size = self.params.polynomial_size;
+        let glwe_size = self.params.glwe_dimension.to_glwe_size();
+
+        // keyswitch to the small LWE key and
+        // rotate every accumulator by the (modulus switched) body
+        let lwes: Vec<_> = inputs
+            .iter()
+            .map(|(ct, _)| {
+                let mut lwe = LweCiphertext::new(0u64, self.params.lwe_dimension.to_lwe_size());
+                keyswitch_lwe_ciphertext(&self.key.key_switching_key, &ct.ct, &mut lwe);
+                lwe
+            })
+            .collect();
+        let mut luts: Vec<_> = inputs
+            .iter()
+            .zip(lwes.iter())
+            .map(|((_, acc), lwe)| {
+                let mut lut = acc.acc.clone();
+                let b = pbs_modulus_switch(*lwe.get_body().data, polynomial_size);
+                lut.as_mut_polynomial_list()
+                    .iter_mut()
+                    .for_each(|mut poly| {
+                        polynomial_wrapping_monic_monomial_div_assign(&mut poly, MonomialDegree(b))
+                    });
+                lut
+            })
+            .collect();
+
+        // the blind rotations, with the bootstrapping key in the outer loop
+        let fft = Fft::new(polynomial_size);
+        let fft = fft.as_view();
+        let mut mem =
+            GlobalMemBuffer::new(cmux_scratch::<u64>(glwe_size, polynomial_size, fft).unwrap());
+        let mut stack = DynStack::new(&mut mem);
+        let mut ct1 = GlweCiphertext::new(0u64, glwe_size, polynomial_size);
+        for (i, ggsw) in self
+            .key
+            .bootstrapping_key
+            .as_view()
+            .into_ggsw_iter()
+            .enumerate()
+        {
+            for (lut, lwe) in luts.iter_mut().zip(lwes.iter()) {
+                let a_i = lwe.get_mask().as_ref()[i];
+                if a_i == 0 {
+                    continue;
+                }
+                ct1.as_mut().copy_from_slice(lut.as_ref());
+                let a = pbs_modulus_switch(a_i, polynomial_size);
+                ct1.as_mut_polynomial_list()
+                    .iter_mut()
+                    .for_each(|mut poly| {
+                        polynomial_wrapping_monic_monomial_mul_assign(&mut poly, MonomialDegree(a))
+                    });
+                cmux(
+                    lut.as_mut_view(),
+                    ct1.as_mut_view(),
+                    ggsw,
+                    fft,
+                    stack.rb_mut(),
+                );
+            }
+        }
+
+        luts.iter()
+            .zip(inputs.iter())
+            .map(|(lut, (_, acc))| {
+                let mut out = self.new_ct();
+                extract_lwe_sample_from_glwe_ciphertext(lut, &mut out.ct, MonomialDegree(0));
+                out.degree = acc.degree;
+                out
+            })
+            .collect()
+    }
+
     /// Compute `min(a, b)` homomorphically with an existing FFT context.
     pub fn min_with_fft(
         &self,
@@ -408,9 +1162,9 @@ impl KnnServer {
 
     /// Compute `min(a, b)` homomorphically.
     pub fn min(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let mut stack = DynStack::new(&mut mem);
-        self.min_with_fft(a, b, fft.as_view(), &mut stack)
+        with_polymul_fft(self.params, |fft, stack| {
+            self.min_with_fft(a, b, fft, stack)
+        })
     }
 
     #[allow(dead_code)]
@@ -421,9 +1175,9 @@ impl KnnServer {
         a: &Ciphertext,
         b: &Ciphertext,
     ) -> Ciphertext {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let mut stack = DynStack::new(&mut mem);
-        let acc = self.trivially_double_ct_acc(a_pt, b_pt, fft.as_view(), &mut stack);
+        let acc = with_polymul_fft(self.params, |fft, stack| {
+            self.trivially_double_ct_acc(a_pt, b_pt, fft, stack)
+        });
 
         let diff = self.special_sub(b, a);
         self.key.keyswitch_programmable_bootstrap(&diff, &acc)
@@ -439,125 +1193,785 @@ impl KnnServer {
         j: &Ciphertext,
         fft: FftView,
         stack: &mut DynStack,
+    ) -> Ciphertext {
+        let diff = self.special_sub(b, a);
+        self.select_with_fft(&diff, i, j, fft, stack)
+    }
+
+    /// Output `i` if `selector` is in the lower half of the plaintext space
+    /// and `j` otherwise, using an existing FFT context.
+    pub fn select_with_fft(
+        &self,
+        selector: &Ciphertext,
+        i: &Ciphertext,
+        j: &Ciphertext,
+        fft: FftView,
+        stack: &mut DynStack,
     ) -> Ciphertext {
         let acc = self.double_ct_acc(i, j, fft, stack);
+        self.key.keyswitch_programmable_bootstrap(selector, &acc)
+    }
 
+    /// Compare the radix values `a` and `b` lexicographically, the blocks are given
+    /// from the most significant one and they must be in the lower half of the plaintext space.
+    /// The output is in the lower half of the plaintext space if `a < b`
+    /// and in the upper half otherwise, so it can be used in `select_with_fft`.
+    /// Every block is compared with one bootstrap into less/equal/greater (0/1/2),
+    /// then the results are folded from the most significant block with one bootstrap per block.
+    /// The blocks are compared sequentially because the comparators call this while they hold
+    /// the read lock of the server, a nested parallel section could make a worker thread
+    /// wait for the same lock behind a writer, e.g., `Registry::load_model`.
+    pub fn lexicographic_selector(&self, a: &[&Ciphertext], b: &[&Ciphertext]) -> Ciphertext {
+        assert_eq!(a.len(), b.len());
+        assert!(!a.is_empty());
+        let half = self.params.message_modulus.0 as u64 / 2;
+
+        // `special_sub(b, a)` is `a - b + t/2`
+        let cmp_acc = self.key.generate_accumulator(|x| match x.cmp(&half) {
+            Ordering::Less => 0,
+            Ordering::Equal => 1,
+            Ordering::Greater => 2,
+        });
+        let cmps: Vec<_> = a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| {
+                let diff = self.special_sub(y, x);
+                self.key.keyswitch_programmable_bootstrap(&diff, &cmp_acc)
+            })
+            .collect();
+
+        // the higher blocks decide unless they are equal
+        let combine = |x: u64| if x / 3 == 1 { x % 3 } else { x / 3 };
+        let combine_acc = self.key.generate_accumulator(combine);
+        let to_selector = |c: u64| if c == 0 { half / 2 } else { half + half / 2 };
+        let final_acc = self.key.generate_accumulator(|x| to_selector(combine(x)));
+
+        let mut state = cmps[0].clone();
+        if cmps.len() == 1 {
+            return self.key.keyswitch_programmable_bootstrap(
+                &state,
+                &self.key.generate_accumulator(to_selector),
+            );
+        }
+        for (i, cmp) in cmps.iter().enumerate().skip(1) {
+            slice_wrapping_scalar_mul_assign(state.ct.as_mut(), 3);
+            self.raw_add_assign(&mut state, cmp);
+            let acc = if i == cmps.len() - 1 {
+                &final_acc
+            } else {
+                &combine_acc
+            };
+            state = self.key.keyswitch_programmable_bootstrap(&state, acc);
+        }
+        state
+    }
+
+    /// Execute `arg_min(a_i, b_j) = if a == min(a_i, b_j) j else i` homomorphically.
+    pub fn arg_min(
+        &self,
+        a: &Ciphertext,
+        b: &Ciphertext,
+        i: &Ciphertext,
+        j: &Ciphertext,
+    ) -> Ciphertext {
+        with_polymul_fft(self.params, |fft, stack| {
+            self.arg_min_with_fft(a, b, i, j, fft, stack)
+        })
+    }
+
+    /// Output an encryption of 1 if `a < b` and an encryption of 0 otherwise.
+    /// Like `min`, the inputs must be in the lower half of the plaintext space.
+    pub fn less_than(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
+        let half = self.params.message_modulus.0 as u64 / 2;
+        let acc = self.key.generate_accumulator(|x| (x < half) as u64);
+
+        // the difference is `a - b + t/2` which is less than `t/2` iff `a < b`
         let diff = self.special_sub(b, a);
         self.key.keyswitch_programmable_bootstrap(&diff, &acc)
     }
 
-    /// Execute `arg_min(a_i, b_j) = if a == min(a_i, b_j) j else i` homomorphically.
-    pub fn arg_min(
-        &self,
-        a: &Ciphertext,
-        b: &Ciphertext,
-        i: &Ciphertext,
-        j: &Ciphertext,
-    ) -> Ciphertext {
-        let (fft, mut mem) = setup_polymul_fft(self.params);
-        let mut stack = DynStack::new(&mut mem);
-        self.arg_min_with_fft(a, b, i, j, fft.as_view(), &mut stack)
+    /// Create an accumulator from `value` such that when used in PBS,
+    /// the output is `value` if the input is 0 and 0 for any other input.
+    pub fn indicator_ct_acc(
+        &self,
+        value: &Ciphertext,
+        fft: FftView,
+        stack: &mut DynStack,
+    ) -> Accumulator {
+        let n = self.params.polynomial_size.0;
+        let chunk_size = n / self.params.message_modulus.0;
+
+        // the polynomial is 1 for the coefficients that correspond to the input 0,
+        // the negative half of the box is at the end due to the negacyclic property
+        let poly = Polynomial::from_container({
+            let mut tmp = vec![0u64; n];
+            for a_i in tmp[0..chunk_size].iter_mut() {
+                *a_i = 1;
+            }
+            for a_i in tmp[0..chunk_size / 2].iter_mut() {
+                *a_i = (*a_i).wrapping_neg();
+            }
+            tmp.rotate_left(chunk_size / 2);
+            tmp
+        });
+
+        let glwe = self.lwe_to_glwe(value);
+        Accumulator {
+            acc: self.polynomial_glwe_mul_with_fft(&glwe, &poly, fft, stack),
+            degree: Degree(self.params.message_modulus.0 - 1),
+        }
+    }
+
+    /// Output `value` if `selector` is an encryption of `target`
+    /// and an encryption of 0 otherwise, using an existing FFT context.
+    /// The difference between `selector` and `target` must be smaller than the message modulus.
+    pub fn select_eq_with_fft(
+        &self,
+        selector: &Ciphertext,
+        target: u64,
+        value: &Ciphertext,
+        fft: FftView,
+        stack: &mut DynStack,
+    ) -> Ciphertext {
+        let acc = self.indicator_ct_acc(value, fft, stack);
+
+        // move `target` to 0 so that it hits the indicator
+        let mut shifted = selector.clone();
+        let shift = Plaintext((target * self.delta()).wrapping_neg());
+        lwe_ciphertext_plaintext_add_assign(&mut shifted.ct, shift);
+        self.key.keyswitch_programmable_bootstrap(&shifted, &acc)
+    }
+
+    /// Output `value` if `selector` is an encryption of `target`
+    /// and an encryption of 0 otherwise.
+    pub fn select_eq(&self, selector: &Ciphertext, target: u64, value: &Ciphertext) -> Ciphertext {
+        with_polymul_fft(self.params, |fft, stack| {
+            self.select_eq_with_fft(selector, target, value, fft, stack)
+        })
+    }
+
+    fn new_ct(&self) -> Ciphertext {
+        Ciphertext {
+            ct: LweCiphertextOwned::new(0u64, LweSize(self.params.polynomial_size.0 + 1)),
+            degree: Degree(self.params.message_modulus.0 - 1),
+            message_modulus: self.params.message_modulus,
+            carry_modulus: self.params.carry_modulus,
+        }
+    }
+
+    /// Create a trivial (noiseless) encryption of `x`, i.e., enc(x) = (0, Delta * x)
+    pub fn trivially_encrypt(&self, x: u64) -> Ciphertext {
+        self.trivially_encrypt_with_delta(x, self.delta())
+    }
+
+    pub fn trivially_encrypt_with_delta(&self, x: u64, delta: u64) -> Ciphertext {
+        let mut out = self.new_ct();
+        let pt = Plaintext(x * delta);
+        trivially_encrypt_lwe_ciphertext(&mut out.ct, pt);
+        out
+    }
+
+    pub fn raw_sub(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        let mut res = self.new_ct();
+        slice_wrapping_sub(res.ct.as_mut(), lhs.ct.as_ref(), rhs.ct.as_ref());
+        res
+    }
+
+    pub fn raw_sub_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        slice_wrapping_sub_assign(lhs.ct.as_mut(), rhs.ct.as_ref())
+    }
+
+    pub fn raw_add(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
+        let mut res = self.new_ct();
+        slice_wrapping_add(res.ct.as_mut(), lhs.ct.as_ref(), rhs.ct.as_ref());
+        res
+    }
+
+    pub fn raw_add_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
+        slice_wrapping_add_assign(lhs.ct.as_mut(), rhs.ct.as_ref())
+    }
+
+    /// Set the model rows, every row must have the same number of features.
+    /// See `check_data` for the errors.
+    pub fn set_data(&mut self, rows: &[Vec<u64>]) -> Result<(), KnnError> {
+        let gamma = rows.iter().fold(0usize, |acc, x| acc.max(x.len()));
+        self.check_data(rows, gamma)?;
+        self.gamma = gamma;
+        self.data.clear();
+        self.fourier_data.clear();
+        self.norms.clear();
+        self.rows.clear();
+        self.push_rows(rows);
+        self.packed_data = self.pack_data();
+        self.features = Feature::from_rows(rows);
+        Ok(())
+    }
+
+    /// The plaintext modulus of the distances, i.e., `initial_modulus` in `setup_with_modulus`.
+    pub fn dist_modulus(&self) -> u64 {
+        (1u64 << 63) / (self.dist_delta * self.params.carry_modulus.0 as u64)
+    }
+
+    /// Check that `rows` can be used as model rows with `gamma` features.
+    /// The number of features must be smaller than the polynomial size
+    /// and the squared distance must be smaller than `dist_modulus / 2`, see `analysis`.
+    pub fn check_data(&self, rows: &[Vec<u64>], gamma: usize) -> Result<(), KnnError> {
+        self.check_data_in_range(rows, gamma, self.query_range)
+    }
+
+    /// Like `check_data` but with `query_range` instead of the query range of the server.
+    pub fn check_data_in_range(
+        &self,
+        rows: &[Vec<u64>],
+        gamma: usize,
+        query_range: Option<ValueRange>,
+    ) -> Result<(), KnnError> {
+        let polynomial_size = self.params.polynomial_size.0;
+        if gamma >= polynomial_size {
+            return Err(KnnError::TooManyFeatures {
+                gamma,
+                polynomial_size,
+            });
+        }
+        if let Some((row, r)) = rows.iter().enumerate().find(|(_, r)| r.len() != gamma) {
+            return Err(KnnError::RowLength {
+                row,
+                len: r.len(),
+                gamma,
+            });
+        }
+
+        analysis_in_range(rows, query_range).check(self.dist_modulus())
+    }
+
+    /// The overflow analysis of `rows` with the declared query range,
+    /// if there is none then the query is assumed to be between zero
+    /// and the highest value of every feature in the model.
+    pub fn analysis(&self, rows: &[Vec<u64>]) -> DistanceAnalysis {
+        analysis_in_range(rows, self.query_range)
+    }
+
+    /// Declare the range of the query features, it is used by `check_data`
+    /// so it needs to be set before the model.
+    pub fn set_query_range(&mut self, range: Option<ValueRange>) {
+        self.query_range = range;
+    }
+
+    pub fn query_range(&self) -> Option<ValueRange> {
+        self.query_range
+    }
+
+    /// Encode `rows` and append them to the model, the packed layout is not updated.
+    fn push_rows(&mut self, rows: &[Vec<u64>]) {
+        let padding = vec![0u64; self.params.polynomial_size.0 - self.gamma];
+        let data: Vec<_> = rows
+            .iter()
+            .map(|v| {
+                PlaintextList::from_container({
+                    let mut v_cloned = v.clone();
+                    v_cloned.reverse();
+                    v_cloned.extend_from_slice(&padding);
+                    v_cloned
+                })
+            })
+            .collect();
+
+        // the rows do not change between queries, so we move every row into the Fourier domain
+        // and compute its squared norm once instead of for every query
+        let fourier_data: Vec<_> = with_polymul_fft(self.params, |fft, stack| {
+            data.iter()
+                .map(|m| polynomial_fft_forward_integer(&m.as_polynomial(), fft, stack))
+                .collect()
+        });
+        self.norms.extend(
+            data.iter()
+                .map(|m| m.iter().map(|x| *x.0 * *x.0).sum::<u64>()),
+        );
+        self.fourier_data.extend(fourier_data);
+        self.data.extend(data);
+        self.rows.extend_from_slice(rows);
+    }
+
+    /// Append `rows` and their `labels` to the model,
+    /// only the new rows are encoded and moved into the Fourier domain.
+    /// Every row must have `gamma` features, unless the model is empty.
+    /// All the labels are encrypted again if the new labels need more blocks.
+    /// The selection networks are created for every query,
+    /// so they always follow the size of the model.
+    /// Rows cannot be inserted when the model has an extra payload from `set_payload`,
+    /// since the new rows would not have one, `KnnError::ExtraPayload` is returned instead.
+    /// In that case remove the payload with an empty `set_payload`, insert the rows
+    /// and set the payload of all the rows again.
+    /// The model is not modified if an error is returned.
+    pub fn insert_rows(&mut self, rows: &[Vec<u64>], labels: &[u64]) -> Result<(), KnnError> {
+        if rows.len() != labels.len() {
+            return Err(KnnError::LabelCount {
+                rows: rows.len(),
+                labels: labels.len(),
+            });
+        }
+        if !self.extra_payload.is_empty() {
+            return Err(KnnError::ExtraPayload);
+        }
+        if self.data.is_empty() {
+            self.set_data(rows)?;
+            return self.set_labels(labels);
+        }
+
+        let mut all_rows = self.rows.clone();
+        all_rows.extend_from_slice(rows);
+        self.check_data(&all_rows, self.gamma)?;
+        let mut all_labels = self.clear_labels.clone();
+        all_labels.extend_from_slice(labels);
+        let n_classes = all_labels.iter().max().map_or(0, |l| *l as usize + 1);
+        let blocks = self.label_blocks.max(self.radix_blocks(n_classes));
+
+        self.push_rows(rows);
+        self.packed_data = self.pack_data();
+        self.update_feature_max();
+        self.set_labels_with_blocks(&all_labels, blocks)
+    }
+
+    /// Remove the rows at `indices` from the model, the other rows keep their order.
+    /// The record id of a row is its index, see `search`,
+    /// so the rows that come after a deleted row get a smaller id.
+    /// The output has the old index of every remaining row, i.e., the new id `i`
+    /// was the id `output[i]` before the deletion.
+    pub fn delete_rows(&mut self, indices: &[usize]) -> Result<Vec<usize>, KnnError> {
+        let n = self.data.len();
+        if let Some(row) = indices.iter().find(|i| **i >= n) {
+            return Err(KnnError::RowOutOfRange {
+                row: *row,
+                n_rows: n,
+            });
+        }
+        let mut keep = vec![true; n];
+        for i in indices {
+            keep[*i] = false;
+        }
+
+        retain_rows(&mut self.data, &keep);
+        retain_rows(&mut self.fourier_data, &keep);
+        retain_rows(&mut self.norms, &keep);
+        retain_rows(&mut self.rows, &keep);
+        retain_rows(&mut self.labels, &keep);
+        retain_rows(&mut self.clear_labels, &keep);
+        if !self.extra_payload.is_empty() {
+            retain_rows(&mut self.extra_payload, &keep);
+        }
+        self.packed_data = self.pack_data();
+        self.update_feature_max();
+        self.update_n_classes();
+        Ok((0..n).filter(|i| keep[*i]).collect())
+    }
+
+    /// Change the label of the row at index `row`,
+    /// all the labels are encrypted again if the new label needs more blocks.
+    pub fn update_label(&mut self, row: usize, label: u64) -> Result<(), KnnError> {
+        if row >= self.clear_labels.len() {
+            return Err(KnnError::RowOutOfRange {
+                row,
+                n_rows: self.clear_labels.len(),
+            });
+        }
+        let blocks = self.radix_blocks(label as usize + 1);
+        if blocks > self.label_blocks {
+            let mut labels = self.clear_labels.clone();
+            labels[row] = label;
+            self.set_labels_with_blocks(&labels, blocks)
+        } else {
+            self.labels[row] = self.encrypt_label(label, self.label_blocks);
+            self.clear_labels[row] = label;
+            self.update_n_classes();
+            Ok(())
+        }
+    }
+
+    /// Update the highest value of every feature, the names are kept.
+    fn update_feature_max(&mut self) {
+        for (feature, new) in self.features.iter_mut().zip(Feature::from_rows(&self.rows)) {
+            feature.max_value = new.max_value;
+        }
+    }
+
+    fn update_n_classes(&mut self) {
+        self.n_classes = self
+            .clear_labels
+            .iter()
+            .max()
+            .map_or(0, |l| *l as usize + 1);
+    }
+
+    /// Lay out the model rows for `DistanceMode::Packed`,
+    /// the first `gamma` coefficients of every row are placed at the offset `r * gamma`.
+    /// There are no wrap-arounds that reach the extracted coefficients
+    /// since `r * gamma + gamma - 1` is always smaller than `N`.
+    fn pack_data(&self) -> Vec<FourierPolynomial<Vec<c64>>> {
+        let rows_per_packing = self.rows_per_packing();
+        if rows_per_packing == 0 {
+            return vec![];
+        }
+        let n = self.params.polynomial_size.0;
+        let gamma = self.gamma;
+        with_polymul_fft(self.params, |fft, stack| {
+            self.data
+                .chunks(rows_per_packing)
+                .map(|rows| {
+                    let mut packed = Polynomial::new(0u64, PolynomialSize(n));
+                    for (r, row) in rows.iter().enumerate() {
+                        packed.as_mut()[r * gamma..(r + 1) * gamma]
+                            .copy_from_slice(&row.as_ref()[..gamma]);
+                    }
+                    polynomial_fft_forward_integer(&packed, fft, stack)
+                })
+                .collect()
+        })
+    }
+
+    /// Set the labels of the model rows, these are the classes for classification
+    /// or the target values for regression, see `regression` for the range of the targets.
+    /// Labels that do not fit in the message modulus are split into several blocks,
+    /// see `set_labels_with_blocks`.
+    pub fn set_labels(&mut self, labels: &[u64]) -> Result<(), KnnError> {
+        let n_classes = labels.iter().max().map_or(0, |l| *l as usize + 1);
+        self.set_labels_with_blocks(labels, self.radix_blocks(n_classes))
+    }
+
+    /// Set the labels of the model rows where every label is split into `blocks` digits
+    /// in base message modulus, the least significant first.
+    /// The blocks are the first ciphertexts of the payload and they are moved
+    /// block by block in the comparators, use `KnnClient::decrypt_blocks` to reassemble them.
+    /// An error is returned if a label does not fit in `blocks` digits.
+    pub fn set_labels_with_blocks(
+        &mut self,
+        labels: &[u64],
+        blocks: usize,
+    ) -> Result<(), KnnError> {
+        self.check_labels(labels, blocks)?;
+        self.labels = labels
+            .iter()
+            .map(|l| self.encrypt_label(*l, blocks))
+            .collect::<Vec<_>>();
+        self.label_blocks = blocks;
+        self.clear_labels = labels.to_vec();
+        self.update_n_classes();
+        Ok(())
+    }
+
+    /// Check that every label fits in `blocks` digits in base message modulus.
+    pub fn check_labels(&self, labels: &[u64], blocks: usize) -> Result<(), KnnError> {
+        if blocks == 0 {
+            return Err(KnnError::NoLabelBlocks);
+        }
+        let base = self.params.message_modulus.0 as u64;
+        let limit = base.checked_pow(blocks as u32).unwrap_or(u64::MAX);
+        match labels.iter().enumerate().find(|(_, l)| **l >= limit) {
+            Some((row, label)) => Err(KnnError::LabelTooLarge {
+                row,
+                label: *label,
+                blocks,
+                base,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn encrypt_label(&self, label: u64, blocks: usize) -> Vec<Ciphertext> {
+        // we do not lower the precision of the labels, so use the "after" delta
+        let delta = self.delta();
+        let base = self.params.message_modulus.0 as u64;
+        (0..blocks)
+            .map(|b| self.trivially_encrypt_with_delta((label / base.pow(b as u32)) % base, delta))
+            .collect()
+    }
+
+    /// Set the rows, the labels and the metadata of the model.
+    pub fn set_model(&mut self, model: &Model) -> Result<(), KnnError> {
+        if model.rows.len() != model.labels.len() {
+            return Err(KnnError::LabelCount {
+                rows: model.rows.len(),
+                labels: model.labels.len(),
+            });
+        }
+        self.set_data(&model.rows)?;
+        self.set_labels(&model.labels)?;
+        self.quantization = model.quantization;
+        self.features = model.features.clone();
+        Ok(())
     }
 
-    fn new_ct(&self) -> Ciphertext {
-        Ciphertext {
-            ct: LweCiphertextOwned::new(0u64, LweSize(self.params.polynomial_size.0 + 1)),
-            degree: Degree(self.params.message_modulus.0 - 1),
-            message_modulus: self.params.message_modulus,
-            carry_modulus: self.params.carry_modulus,
+    /// The model that is currently used by the server, without any key material.
+    pub fn model(&self) -> Model {
+        Model {
+            rows: self.rows.clone(),
+            labels: self.clear_labels.clone(),
+            gamma: self.gamma,
+            quantization: self.quantization,
+            features: self.features.clone(),
         }
     }
 
-    /// Create a trivial (noiseless) encryption of `x`, i.e., enc(x) = (0, Delta * x)
-    pub fn trivially_encrypt(&self, x: u64) -> Ciphertext {
-        self.trivially_encrypt_with_delta(x, self.delta())
+    /// Write the model to `path`, it can be loaded by a server with different keys.
+    pub fn save_model<P: AsRef<Path>>(&self, path: P) -> bincode::Result<()> {
+        self.model().save(path)
     }
 
-    pub fn trivially_encrypt_with_delta(&self, x: u64, delta: u64) -> Ciphertext {
-        let mut out = self.new_ct();
-        let pt = Plaintext(x * delta);
-        trivially_encrypt_lwe_ciphertext(&mut out.ct, pt);
-        out
+    /// Load the model from a file written by `save_model`.
+    pub fn load_model<P: AsRef<Path>>(&mut self, path: P) -> Result<(), KnnError> {
+        let model = Model::load_file(path)?;
+        self.set_model(&model)
     }
 
-    pub fn raw_sub(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
-        let mut res = self.new_ct();
-        slice_wrapping_sub(res.ct.as_mut(), lhs.ct.as_ref(), rhs.ct.as_ref());
-        res
+    /// The number of ciphertexts used for every label.
+    pub fn label_blocks(&self) -> usize {
+        self.label_blocks
     }
 
-    pub fn raw_sub_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
-        slice_wrapping_sub_assign(lhs.ct.as_mut(), rhs.ct.as_ref())
+    /// The number of digits in base message modulus that are needed
+    /// to represent all the values smaller than `n`, it is at least 1.
+    fn radix_blocks(&self, n: usize) -> usize {
+        let base = self.params.message_modulus.0;
+        let mut blocks = 1;
+        while base.pow(blocks as u32) < n {
+            blocks += 1;
+        }
+        blocks
     }
 
-    pub fn raw_add(&self, lhs: &Ciphertext, rhs: &Ciphertext) -> Ciphertext {
-        let mut res = self.new_ct();
-        slice_wrapping_add(res.ct.as_mut(), lhs.ct.as_ref(), rhs.ct.as_ref());
-        res
+    /// The number of classes in the model, i.e., the highest label plus one.
+    pub fn n_classes(&self) -> usize {
+        self.n_classes
     }
 
-    pub fn raw_add_assign(&self, lhs: &mut Ciphertext, rhs: &Ciphertext) {
-        slice_wrapping_add_assign(lhs.ct.as_mut(), rhs.ct.as_ref())
+    /// Count how many times every class appears in `labels` homomorphically,
+    /// the output is the encrypted vote histogram with one ciphertext per class.
+    /// The client can turn it into class probabilities using `KnnClient::decrypt_histogram`.
+    /// The number of labels must be smaller than the message modulus
+    /// and the labels must have one block, otherwise an error is returned.
+    pub fn class_counts(&self, labels: &[Ciphertext]) -> Result<Vec<Ciphertext>, KnnError> {
+        self.check_single_block_labels()?;
+        let limit = self.params.message_modulus.0 as u64;
+        if labels.len() as u64 >= limit {
+            return Err(KnnError::VoteOverflow {
+                max_score: labels.len() as u64,
+                limit,
+            });
+        }
+        Ok((0..self.n_classes as u64)
+            .into_par_iter()
+            .map(|class| {
+                let acc = self.key.generate_accumulator(|x| (x == class) as u64);
+                let mut count = self.trivially_encrypt(0);
+                for l in labels {
+                    let hit = self.key.keyswitch_programmable_bootstrap(l, &acc);
+                    self.raw_add_assign(&mut count, &hit);
+                }
+                count
+            })
+            .collect())
+    }
+
+    fn check_single_block_labels(&self) -> Result<(), KnnError> {
+        if self.label_blocks != 1 {
+            return Err(KnnError::MultiBlockLabels {
+                label_blocks: self.label_blocks,
+            });
+        }
+        Ok(())
     }
 
-    pub fn set_data(&mut self, data: &[Vec<u64>]) {
-        let gamma = data.iter().fold(0usize, |acc, x| acc.max(x.len()));
-        let padding = vec![0u64; self.params.polynomial_size.0 - gamma];
-        let data: Vec<_> = data
+    /// Find the index of the highest score homomorphically,
+    /// all the scores must be at most `max_score`
+    /// which should be in the lower half of the plaintext space.
+    /// The scores are turned into `max_score - score` and then
+    /// a tournament of `min`/`arg_min` is used to find the smallest one.
+    /// Ties are broken in favour of the smallest index, like `clear_knn::weighted_majority`.
+    /// An error is returned if `scores` is empty or `max_score` is not in the lower half.
+    pub fn arg_max(&self, scores: &[Ciphertext], max_score: u64) -> Result<Ciphertext, KnnError> {
+        if scores.is_empty() {
+            return Err(KnnError::NoClasses);
+        }
+        let limit = self.params.message_modulus.0 as u64 / 2;
+        if max_score >= limit {
+            return Err(KnnError::VoteOverflow { max_score, limit });
+        }
+
+        let max_score = self.trivially_encrypt(max_score);
+        let mut items: Vec<_> = scores
             .iter()
-            .map(|v| {
-                PlaintextList::from_container({
-                    let mut v_cloned = v.clone();
-                    v_cloned.reverse();
-                    v_cloned.extend_from_slice(&padding);
-                    v_cloned
-                })
+            .enumerate()
+            .map(|(i, score)| {
+                EncItem::new(
+                    self.raw_sub(&max_score, score),
+                    self.trivially_encrypt(i as u64),
+                )
             })
             .collect();
 
-        self.gamma = gamma;
-        self.data = data;
+        while items.len() > 1 {
+            let carry = if items.len() % 2 == 1 {
+                items.pop()
+            } else {
+                None
+            };
+            items = items
+                .par_chunks_exact(2)
+                .map(|pair| {
+                    // arg_min keeps its second argument on a tie,
+                    // which is the item with the smaller index
+                    let (a, b) = (&pair[1], &pair[0]);
+                    with_polymul_fft(self.params, |fft, stack| {
+                        EncItem::new(
+                            self.min_with_fft(&a.value, &b.value, fft, stack),
+                            self.arg_min_with_fft(
+                                &a.value,
+                                &b.value,
+                                a.class(),
+                                b.class(),
+                                fft,
+                                stack,
+                            ),
+                        )
+                    })
+                })
+                .collect();
+            items.extend(carry);
+        }
+        Ok(items.pop().unwrap().payload.swap_remove(0))
     }
 
-    pub fn set_labels(&mut self, labels: &[u64]) {
-        // we do not lower the precision of the labels, so use the "after" delta
-        let delta = self.delta();
-        self.labels = labels
-            .iter()
-            .map(|l| self.trivially_encrypt_with_delta(*l, delta))
-            .collect::<Vec<_>>();
+    /// Compute the majority class of `labels` homomorphically,
+    /// only one encrypted class is returned so the labels of the neighbours are not revealed.
+    /// The number of labels must be smaller than half of the message modulus.
+    pub fn majority(&self, labels: &[Ciphertext]) -> Result<Ciphertext, KnnError> {
+        let counts = self.class_counts(labels)?;
+        self.arg_max(&counts, labels.len() as u64)
+    }
+
+    /// Sum the selected `targets` homomorphically and output the sum or the rounded mean,
+    /// use `KnnClient::decode_regression` to get the mean.
+    /// The sum is computed in the plaintext space, so `k * max_target` must be smaller
+    /// than the message modulus, where `max_target` is the highest label given to `set_labels`,
+    /// otherwise `KnnError::RegressionOverflow` is returned.
+    /// The targets must be single block labels and there must be at least one.
+    pub fn regression(
+        &self,
+        targets: &[Ciphertext],
+        output: RegressionOutput,
+    ) -> Result<Ciphertext, KnnError> {
+        if targets.is_empty() {
+            return Err(KnnError::NoNeighbours);
+        }
+        self.check_single_block_labels()?;
+        let k = targets.len() as u64;
+        let max_target = self.n_classes.saturating_sub(1) as u64;
+        if k * max_target >= self.params.message_modulus.0 as u64 {
+            return Err(KnnError::RegressionOverflow {
+                k: targets.len(),
+                max_target,
+                message_modulus: self.params.message_modulus.0,
+            });
+        }
+
+        let mut sum = self.trivially_encrypt(0);
+        for t in targets {
+            self.raw_add_assign(&mut sum, t);
+        }
+        Ok(match output {
+            RegressionOutput::ExactMean => sum,
+            RegressionOutput::RoundedMean => {
+                let acc = self.key.generate_accumulator(|x| (x + k / 2) / k);
+                self.key.keyswitch_programmable_bootstrap(&sum, &acc)
+            }
+        })
+    }
+
+    /// The highest weight a neighbour can have when `k` neighbours vote,
+    /// so that every class score stays in the lower half of the plaintext space.
+    pub fn max_vote_weight(&self, k: usize) -> u64 {
+        (self.params.message_modulus.0 as u64 / 2 - 1) / k as u64
+    }
+
+    /// Sum the weights of the neighbours per class homomorphically.
+    /// The weight of every neighbour is computed from its distance in `distances` with PBS
+    /// and then added to the score of its class in `labels` using `select_eq`.
+    /// An error is returned if there is not one distance per label, if the labels
+    /// have more than one block or if the neighbours do not fit in the lower half
+    /// of the plaintext space even with a weight of 1.
+    pub fn weighted_class_scores(
+        &self,
+        distances: &[Ciphertext],
+        labels: &[Ciphertext],
+        weight: VoteWeight,
+    ) -> Result<Vec<Ciphertext>, KnnError> {
+        if distances.len() != labels.len() {
+            return Err(KnnError::LabelCount {
+                rows: distances.len(),
+                labels: labels.len(),
+            });
+        }
+        if labels.is_empty() {
+            return Err(KnnError::NoNeighbours);
+        }
+        self.check_single_block_labels()?;
+        let max_weight = self.max_vote_weight(labels.len());
+        if max_weight == 0 {
+            return Err(KnnError::VoteOverflow {
+                max_score: labels.len() as u64,
+                limit: self.params.message_modulus.0 as u64 / 2,
+            });
+        }
+
+        let acc = self
+            .key
+            .generate_accumulator(|d| weight.weight(d, max_weight));
+        let weights: Vec<_> = distances
+            .par_iter()
+            .map(|d| self.key.keyswitch_programmable_bootstrap(d, &acc))
+            .collect();
+
+        Ok((0..self.n_classes as u64)
+            .into_par_iter()
+            .map(|class| {
+                with_polymul_fft(self.params, |fft, stack| {
+                    let mut score = self.trivially_encrypt(0);
+                    for (l, w) in labels.iter().zip(weights.iter()) {
+                        let hit = self.select_eq_with_fft(l, class, w, fft, stack);
+                        self.raw_add_assign(&mut score, &hit);
+                    }
+                    score
+                })
+            })
+            .collect())
+    }
+
+    /// Compute the class with the highest sum of weights homomorphically,
+    /// see `weighted_class_scores` for how the weights are computed.
+    pub fn weighted_majority(
+        &self,
+        distances: &[Ciphertext],
+        labels: &[Ciphertext],
+        weight: VoteWeight,
+    ) -> Result<Ciphertext, KnnError> {
+        let scores = self.weighted_class_scores(distances, labels, weight)?;
+        let max_score = labels.len() as u64 * self.max_vote_weight(labels.len());
+        self.arg_max(&scores, max_score)
     }
 }
 
-fn setup_with_modulus(params: Parameters, dist_modulus: u64) -> (KnnClient, KnnServer) {
-    assert!(dist_modulus.is_power_of_two());
-
-    let mut seeder = new_seeder();
-    let mut encryption_rng =
-        EncryptionRandomGenerator::<ActivatedRandomGenerator>::new(seeder.seed(), seeder.as_mut());
-    let (client_key, server_key) = gen_keys(params);
-    let lwe_to_glwe_ksk = crate::gen_ksk(&client_key, &mut encryption_rng);
-
-    let dist_delta = (1u64 << 63) / (dist_modulus * params.carry_modulus.0 as u64);
-    assert_eq!(dist_delta % 2, 0);
-    assert!((params.message_modulus.0 as u64) < dist_delta);
-    (
-        KnnClient {
-            key: client_key,
-            params,
-            encryption_rng,
-            dist_delta,
-        },
-        KnnServer {
-            key: server_key,
-            lwe_to_glwe_ksk,
-            params,
-            dist_delta,
-            gamma: 0,
-            data: vec![],
-            labels: vec![],
-        },
-    )
+/// Setup the server and client where the distances are computed with `dist_modulus`,
+/// see `KnnClient::keygen`.
+pub fn setup_with_modulus(params: Parameters, dist_modulus: u64) -> (KnnClient, KnnServer) {
+    let (client, eval_keys) = KnnClient::keygen(params, dist_modulus);
+    let server = KnnServer::from_evaluation_keys(eval_keys).expect("keygen creates valid keys");
+    (client, server)
+}
+
+/// Keep the elements of `v` where `keep` is true.
+fn retain_rows<T>(v: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    v.retain(|_| *keep.next().unwrap());
 }
 
 pub fn setup(params: Parameters) -> (KnnClient, KnnServer) {
@@ -567,16 +1981,35 @@ pub fn setup(params: Parameters) -> (KnnClient, KnnServer) {
 
 /// Setup the server and client with a model, specified by `data` and `labels`.
 /// The data should not be encoded.
+/// An error is returned if the model does not fit in `params` and `dist_modulus`,
+/// see `KnnServer::check_data`.
 pub fn setup_with_data(
     params: Parameters,
     data: &[Vec<u64>],
     labels: &[u64],
     dist_modulus: u64,
-) -> (KnnClient, KnnServer) {
+) -> Result<(KnnClient, KnnServer), KnnError> {
+    if data.len() != labels.len() {
+        return Err(KnnError::LabelCount {
+            rows: data.len(),
+            labels: labels.len(),
+        });
+    }
     let (client, mut server) = setup_with_modulus(params, dist_modulus);
-    server.set_data(data);
-    server.set_labels(labels);
-    (client, server)
+    server.set_data(data)?;
+    server.set_labels(labels)?;
+    Ok((client, server))
+}
+
+/// Like `setup_with_data` but the parameters are given by the name of a built-in preset,
+/// see `PresetRegistry`.
+pub fn setup_with_preset(
+    name: &str,
+    data: &[Vec<u64>],
+    labels: &[u64],
+    dist_modulus: u64,
+) -> Result<(KnnClient, KnnServer), KnnError> {
+    setup_with_data(preset(name)?, data, labels, dist_modulus)
 }
 
 #[cfg(test)]
@@ -589,24 +2022,7 @@ pub mod test {
     use std::sync::{Arc, Mutex, RwLock};
     use tfhe::shortint::prelude::*;
 
-    pub(crate) const TEST_PARAM: Parameters = Parameters {
-        lwe_dimension: LweDimension(742),
-        glwe_dimension: GlweDimension(1),
-        polynomial_size: PolynomialSize(2048),
-        lwe_modular_std_dev: StandardDev(0.000007069849454709433),
-        glwe_modular_std_dev: StandardDev(0.00000000000000029403601535432533),
-        pbs_level: DecompositionLevelCount(6),
-        pbs_base_log: DecompositionBaseLog(3),
-        ks_level: DecompositionLevelCount(6),
-        ks_base_log: DecompositionBaseLog(3),
-        pfks_level: DecompositionLevelCount(6),
-        pfks_base_log: DecompositionBaseLog(3),
-        pfks_modular_std_dev: StandardDev(0.00000000000000029403601535432533),
-        cbs_level: DecompositionLevelCount(0),
-        cbs_base_log: DecompositionBaseLog(0),
-        message_modulus: MessageModulus(32),
-        carry_modulus: CarryModulus(1),
-    };
+    pub(crate) const TEST_PARAM: Parameters = crate::params::TEST_PARAMS;
 
     fn decode(params: Parameters, x: u64) -> u64 {
         let delta = (1u64 << 63) / (params.message_modulus.0 * params.carry_modulus.0) as u64;
@@ -795,6 +2211,31 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_batch_bootstrap() {
+        let (client, server) = setup(TEST_PARAM);
+        let (fft, mut mem) = setup_polymul_fft(TEST_PARAM);
+        let mut stack = DynStack::new(&mut mem);
+
+        let modulus = server.params.message_modulus.0 as u64;
+        let inputs: Vec<_> = (0..modulus / 2)
+            .map(|x| {
+                let a = client.key.encrypt(x);
+                let b = client.key.encrypt(modulus / 2 - 1 - x);
+                let acc = server.double_ct_acc(&a, &b, fft.as_view(), &mut stack);
+                (server.special_sub(&b, &a), acc)
+            })
+            .collect();
+
+        let actual = server.batch_keyswitch_programmable_bootstrap(&inputs);
+        assert_eq!(actual.len(), inputs.len());
+        for (x, ct) in actual.iter().enumerate() {
+            let x = x as u64;
+            let expected = x.min(modulus / 2 - 1 - x);
+            assert_eq!(client.key.decrypt(ct), expected);
+        }
+    }
+
     #[test]
     fn test_enc_sort() {
         let (client, server) = setup(TEST_PARAM);
@@ -896,6 +2337,443 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_majority() {
+        let (client, mut server) = setup(TEST_PARAM);
+        server.set_labels(&[0, 1, 2, 3]).unwrap();
+        for labels in [vec![1u64, 2, 1], vec![3, 3, 0, 2, 3], vec![0, 2, 2, 2, 1]] {
+            let cts: Vec<_> = labels.iter().map(|l| client.key.encrypt(*l)).collect();
+
+            let counts = server.class_counts(&cts).unwrap();
+            let actual_counts: Vec<_> = counts.iter().map(|c| client.key.decrypt(c)).collect();
+            let expected_counts: Vec<_> = (0..server.n_classes() as u64)
+                .map(|c| labels.iter().filter(|l| **l == c).count() as u64)
+                .collect();
+            assert_eq!(actual_counts, expected_counts);
+            assert_eq!(
+                client.decrypt_histogram(&counts),
+                crate::clear_knn::histogram(&labels, server.n_classes())
+            );
+
+            let actual = client.key.decrypt(&server.majority(&cts).unwrap());
+            assert_eq!(actual, crate::clear_knn::majority(&labels));
+        }
+
+        // 16 votes do not fit in the lower half of the message modulus 32
+        let cts: Vec<_> = (0..16).map(|_| client.key.encrypt(1)).collect();
+        assert!(matches!(
+            server.majority(&cts),
+            Err(KnnError::VoteOverflow {
+                max_score: 16,
+                limit: 16
+            })
+        ));
+        assert!(matches!(server.arg_max(&[], 1), Err(KnnError::NoClasses)));
+        assert!(matches!(
+            server.weighted_class_scores(&cts[..1], &cts[..2], VoteWeight::Uniform),
+            Err(KnnError::LabelCount { .. })
+        ));
+        server.set_labels(&[0, 40]).unwrap();
+        assert!(matches!(
+            server.class_counts(&cts[..2]),
+            Err(KnnError::MultiBlockLabels { label_blocks: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_payload() {
+        let (mut client, mut server) = setup(TEST_PARAM);
+        let data = vec![
+            vec![2, 0, 0, 0u64],
+            vec![0, 0, 0, 1],
+            vec![1, 1, 1, 0],
+            vec![0, 3, 0, 0],
+        ];
+        server.set_data(&data).unwrap();
+        server.set_labels(&[0, 1, 2, 3]).unwrap();
+        assert!(matches!(
+            server.set_payload(&[vec![10, 4], vec![11, 5], vec![12, 6]]),
+            Err(KnnError::PayloadCount {
+                rows: 4,
+                payloads: 3
+            })
+        ));
+        assert!(matches!(
+            server.set_payload(&[vec![10, 4], vec![11], vec![12, 6], vec![13, 7]]),
+            Err(KnnError::PayloadLength {
+                row: 1,
+                len: 1,
+                width: 2
+            })
+        ));
+        assert!(matches!(
+            server.set_payload(&[vec![10, 4], vec![11, 5], vec![12, 32], vec![13, 7]]),
+            Err(KnnError::PayloadTooLarge {
+                row: 2,
+                value: 32,
+                message_modulus: 32
+            })
+        ));
+        assert_eq!(server.payload_len(), 1);
+        server
+            .set_payload(&[vec![10, 4], vec![11, 5], vec![12, 6], vec![13, 7]])
+            .unwrap();
+        assert_eq!(server.payload_len(), 3);
+
+        let target = vec![0, 0, 0, 0u64];
+        let (glwe, lwe) = client.make_query(&target);
+        let items: Vec<_> = server
+            .compute_distances_with_labels(&glwe, &lwe)
+            .into_iter()
+            .map(|item| Arc::new(Mutex::new(item)))
+            .collect();
+
+        let server = Arc::new(RwLock::new(server));
+        let cmp = AsyncEncComparator::new(server, TEST_PARAM);
+        let sorter = BatcherSort::par_new_k(2, cmp, false);
+        sorter.par_sort(&items);
+
+        // the distances are 4, 1, 3, 9 so the two nearest rows are 1 and 2
+        let actual: Vec<Vec<_>> = items[..2]
+            .iter()
+            .map(|item| {
+                item.lock()
+                    .unwrap()
+                    .payload
+                    .iter()
+                    .map(|ct| client.key.decrypt(ct))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(actual, vec![vec![1, 11, 5], vec![2, 12, 6]]);
+    }
+
+    #[test]
+    fn test_multi_block_labels() {
+        let (mut client, mut server) = setup(TEST_PARAM);
+        let data = vec![
+            vec![2, 0, 0, 0u64],
+            vec![0, 0, 0, 1],
+            vec![1, 1, 1, 0],
+            vec![0, 3, 0, 0],
+        ];
+        // 32 classes fit in one block so the highest label needs two
+        let labels = vec![5, 1000, 32, 31];
+        server.set_data(&data).unwrap();
+        server.set_labels(&labels).unwrap();
+        assert_eq!(server.label_blocks(), 2);
+        assert_eq!(server.payload_len(), 2);
+
+        let target = vec![0, 0, 0, 0u64];
+        let (glwe, lwe) = client.make_query(&target);
+        let items: Vec<_> = server
+            .compute_distances_with_labels(&glwe, &lwe)
+            .into_iter()
+            .map(|item| Arc::new(Mutex::new(item)))
+            .collect();
+
+        let server = Arc::new(RwLock::new(server));
+        let cmp = AsyncEncComparator::new(server, TEST_PARAM);
+        let sorter = BatcherSort::par_new_k(3, cmp, false);
+        sorter.par_sort(&items);
+
+        // the distances are 4, 1, 3, 9
+        let actual: Vec<_> = items[..3]
+            .iter()
+            .map(|item| client.decrypt_blocks(&item.lock().unwrap().payload))
+            .collect();
+        assert_eq!(actual, vec![1000, 32, 5]);
+    }
+
+    #[test]
+    fn test_invalid_model() {
+        let (_, mut server) = setup(TEST_PARAM);
+        assert!(matches!(
+            server.set_labels_with_blocks(&[3, 32], 1),
+            Err(KnnError::LabelTooLarge { row: 1, .. })
+        ));
+        assert!(matches!(
+            server.set_labels_with_blocks(&[3, 32], 0),
+            Err(KnnError::NoLabelBlocks)
+        ));
+        assert!(matches!(
+            server.set_distance_blocks(0),
+            Err(KnnError::DistanceBlocks { blocks: 0, .. })
+        ));
+        // the distance modulus is the message modulus, so there is nothing to split
+        assert!(matches!(
+            server.set_distance_blocks(2),
+            Err(KnnError::DistanceBlocks {
+                blocks: 2,
+                ratio: 1,
+                ..
+            })
+        ));
+        assert_eq!(server.distance_blocks(), 1);
+        assert!(matches!(
+            server.set_data(&[vec![0u64; 2048]]),
+            Err(KnnError::TooManyFeatures { gamma: 2048, .. })
+        ));
+        assert!(matches!(
+            server.set_data(&[vec![0, 0, 0, 1u64], vec![1, 2]]),
+            Err(KnnError::RowLength { row: 1, .. })
+        ));
+        // the distances must be smaller than half of the distance modulus 32,
+        // 5^2 + 2^2 fits in the modulus but not in the lower half
+        assert!(matches!(
+            server.set_data(&[vec![6, 0, 0, 0u64]]),
+            Err(KnnError::DistanceOverflow {
+                max_distance: 36,
+                dist_modulus: 32
+            })
+        ));
+        assert!(matches!(
+            server.set_data(&[vec![5, 0, 0, 0u64], vec![0, 2, 0, 0]]),
+            Err(KnnError::DistanceOverflow {
+                max_distance: 29,
+                dist_modulus: 32
+            })
+        ));
+        server
+            .set_data(&[vec![3, 0, 0, 0u64], vec![0, 2, 0, 0]])
+            .unwrap();
+        server.set_labels(&[0, 1]).unwrap();
+
+        assert!(matches!(
+            server.insert_rows(&[vec![1, 2]], &[1]),
+            Err(KnnError::RowLength { row: 2, .. })
+        ));
+        assert!(matches!(
+            server.insert_rows(&[vec![1, 2, 0, 0]], &[]),
+            Err(KnnError::LabelCount { .. })
+        ));
+        assert!(matches!(
+            server.delete_rows(&[2]),
+            Err(KnnError::RowOutOfRange { row: 2, n_rows: 2 })
+        ));
+        assert!(matches!(
+            server.update_label(3, 0),
+            Err(KnnError::RowOutOfRange { row: 3, n_rows: 2 })
+        ));
+        // the model is not modified by the errors
+        assert_eq!(server.n_rows(), 2);
+
+        // the distance to a query in [0, 4] can be 4^2 + 4^2 + 4^2 + 4^2
+        server.set_query_range(Some(ValueRange::new(0, 4)));
+        assert!(matches!(
+            server.set_data(&[vec![3, 0, 0, 0u64], vec![0, 2, 0, 0]]),
+            Err(KnnError::DistanceOverflow {
+                max_distance: 64,
+                dist_modulus: 32
+            })
+        ));
+        server.set_query_range(Some(ValueRange::new(0, 2)));
+        assert!(server.set_data(&[vec![1, 0, 0, 0u64]]).is_ok());
+    }
+
+    #[test]
+    fn test_insert_delete_rows() {
+        let (mut client, mut server) = setup(TEST_PARAM);
+        server
+            .set_data(&[vec![2, 0, 0, 0u64], vec![0, 0, 0, 1], vec![1, 1, 1, 0]])
+            .unwrap();
+        server.set_labels(&[0, 1, 2]).unwrap();
+
+        // the new label needs two blocks
+        server.insert_rows(&[vec![0, 0, 3, 0]], &[40]).unwrap();
+        assert_eq!(server.delete_rows(&[0]).unwrap(), vec![1, 2, 3]);
+        server.update_label(0, 5).unwrap();
+        assert_eq!(server.n_rows(), 3);
+
+        // the payload has to be removed before inserting rows
+        server.set_payload(&[vec![7], vec![8], vec![9]]).unwrap();
+        assert!(matches!(
+            server.insert_rows(&[vec![0, 0, 0, 2]], &[1]),
+            Err(KnnError::ExtraPayload)
+        ));
+        server.set_payload(&[]).unwrap();
+        assert_eq!(server.payload_len(), 2);
+        assert_eq!(server.label_blocks(), 2);
+        assert_eq!(server.n_classes(), 41);
+        assert_eq!(server.model().features[2].max_value, 3);
+
+        let (glwe, lwe) = client.make_query(&[0, 0, 0, 0]);
+        for mode in [DistanceMode::Plain, DistanceMode::Packed] {
+            server.set_distance_mode(mode);
+            let actual: Vec<_> = server
+                .compute_distances_with_labels(&glwe, &lwe)
+                .iter()
+                .map(|item| {
+                    (
+                        client.key.decrypt(&item.value),
+                        client.decrypt_blocks(&item.payload[..2]),
+                    )
+                })
+                .collect();
+            assert_eq!(actual, vec![(1, 5), (3, 2), (9, 40)]);
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let (mut client, mut server) = setup(TEST_PARAM);
+        let data = vec![
+            vec![3, 0, 0, 0u64],
+            vec![0, 1, 0, 0],
+            vec![2, 2, 0, 0],
+            vec![0, 0, 1, 0],
+            vec![3, 1, 0, 0],
+        ];
+        server.set_data(&data).unwrap();
+        server.set_labels(&[0, 1, 0, 1, 0]).unwrap();
+        assert_eq!(server.id_blocks(), 1);
+
+        let target = vec![0, 0, 0, 0u64];
+        let k = 2;
+        let (glwe, lwe) = client.make_query(&target);
+        let server = Arc::new(RwLock::new(server));
+        let ids = KnnServer::search(&server, k, &glwe, &lwe);
+
+        // the distances are 9, 1, 8, 1, 10 so the two nearest rows are 1 and 3
+        let mut actual: Vec<_> = ids.iter().map(|id| client.decrypt_id(id)).collect();
+        actual.sort();
+        assert_eq!(actual, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_regression() {
+        let (client, mut server) = setup(TEST_PARAM);
+        server.set_labels(&[0, 3, 7, 10]).unwrap();
+        let targets = vec![3u64, 10, 7];
+        let cts: Vec<_> = targets.iter().map(|t| client.key.encrypt(*t)).collect();
+
+        let sum = server
+            .regression(&cts, RegressionOutput::ExactMean)
+            .unwrap();
+        assert_eq!(client.key.decrypt(&sum), 20);
+        assert_eq!(
+            client.decode_regression(&sum, targets.len(), RegressionOutput::ExactMean),
+            crate::clear_knn::mean(&targets)
+        );
+        let mean = server
+            .regression(&cts, RegressionOutput::RoundedMean)
+            .unwrap();
+        assert_eq!(
+            client.decode_regression(&mean, targets.len(), RegressionOutput::RoundedMean),
+            7f64
+        );
+
+        // 4 * 10 does not fit in the message modulus 32
+        let cts: Vec<_> = [3u64, 10, 7, 0]
+            .iter()
+            .map(|t| client.key.encrypt(*t))
+            .collect();
+        assert!(matches!(
+            server.regression(&cts, RegressionOutput::ExactMean),
+            Err(KnnError::RegressionOverflow {
+                k: 4,
+                max_target: 10,
+                message_modulus: 32
+            })
+        ));
+        assert!(matches!(
+            server.regression(&[], RegressionOutput::ExactMean),
+            Err(KnnError::NoNeighbours)
+        ));
+
+        // the targets have two blocks
+        server.set_labels(&[0, 40]).unwrap();
+        assert!(matches!(
+            server.regression(&cts[..1], RegressionOutput::ExactMean),
+            Err(KnnError::MultiBlockLabels { label_blocks: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_weighted_majority() {
+        let (client, mut server) = setup(TEST_PARAM);
+        server.set_labels(&[0, 1, 2]).unwrap();
+        let k = 3;
+        let max_weight = server.max_vote_weight(k);
+
+        // the closest neighbour is outvoted in the plain majority but not when weighted
+        let items = vec![(0u64, 2u64), (5, 1), (6, 1)];
+        let distances: Vec<_> = items.iter().map(|(d, _)| client.key.encrypt(*d)).collect();
+        let labels: Vec<_> = items.iter().map(|(_, l)| client.key.encrypt(*l)).collect();
+        let clear_items: Vec<_> = items
+            .iter()
+            .map(|(d, l)| crate::ClearItem {
+                value: *d,
+                class: *l,
+            })
+            .collect();
+
+        for weight in [
+            VoteWeight::Uniform,
+            VoteWeight::InverseDistance,
+            VoteWeight::Step(1),
+        ] {
+            let scores = server
+                .weighted_class_scores(&distances, &labels, weight)
+                .unwrap();
+            let actual_scores: Vec<_> = scores.iter().map(|c| client.key.decrypt(c)).collect();
+            let expected_scores: Vec<_> = (0..server.n_classes() as u64)
+                .map(|c| {
+                    items
+                        .iter()
+                        .filter(|(_, l)| *l == c)
+                        .map(|(d, _)| weight.weight(*d, max_weight))
+                        .sum::<u64>()
+                })
+                .collect();
+            assert_eq!(actual_scores, expected_scores);
+
+            let maj = server
+                .weighted_majority(&distances, &labels, weight)
+                .unwrap();
+            let actual = client.key.decrypt(&maj);
+            let expected =
+                crate::clear_knn::weighted_majority(&clear_items, |d| weight.weight(d, max_weight));
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_vote_ties() {
+        let (client, mut server) = setup(TEST_PARAM);
+        server.set_labels(&[0, 1, 2, 3]).unwrap();
+
+        // the tournament has a carry and ties in both halves
+        let scores: Vec<_> = [1u64, 3, 0, 3, 3]
+            .iter()
+            .map(|s| client.key.encrypt(*s))
+            .collect();
+        assert_eq!(client.key.decrypt(&server.arg_max(&scores, 3).unwrap()), 1);
+
+        // classes 1 and 3 have the same weight
+        let items = vec![(4u64, 3u64), (4, 1), (9, 2)];
+        let distances: Vec<_> = items.iter().map(|(d, _)| client.key.encrypt(*d)).collect();
+        let labels: Vec<_> = items.iter().map(|(_, l)| client.key.encrypt(*l)).collect();
+        let clear_items: Vec<_> = items
+            .iter()
+            .map(|(d, l)| crate::ClearItem {
+                value: *d,
+                class: *l,
+            })
+            .collect();
+        let max_weight = server.max_vote_weight(items.len());
+        for weight in [VoteWeight::InverseDistance, VoteWeight::Step(5)] {
+            let maj = server
+                .weighted_majority(&distances, &labels, weight)
+                .unwrap();
+            let actual = client.key.decrypt(&maj);
+            let expected =
+                crate::clear_knn::weighted_majority(&clear_items, |d| weight.weight(d, max_weight));
+            assert_eq!(expected, 1);
+            assert_eq!(actual, expected);
+        }
+    }
+
     #[test]
     fn test_compute_distance() {
         let (mut client, mut server) = setup(TEST_PARAM);
@@ -903,7 +2781,7 @@ pub mod test {
             // distance should be 2^2 + 1 = 5
             let data = vec![vec![0, 1, 0, 0u64]];
             let target = vec![2, 0, 0, 0u64];
-            server.set_data(&data);
+            server.set_data(&data).unwrap();
             let (glwe, lwe) = client.make_query(&target);
             let distances = server.compute_distances(&glwe, &lwe);
 
@@ -914,7 +2792,7 @@ pub mod test {
             // distance should be 2^2 = 4
             let data = vec![vec![0, 0, 1, 3u64]];
             let target = vec![0, 0, 1, 1u64];
-            server.set_data(&data);
+            server.set_data(&data).unwrap();
             let (glwe, lwe) = client.make_query(&target);
             let distances = server.compute_distances(&glwe, &lwe);
 
@@ -924,7 +2802,7 @@ pub mod test {
         {
             let data = vec![vec![2, 0, 0, 0u64]];
             let target = vec![4, 2, 0, 0u64];
-            server.set_data(&data);
+            server.set_data(&data).unwrap();
             let (glwe, lwe) = client.make_query(&target);
             let distances = server.compute_distances(&glwe, &lwe);
 
@@ -941,6 +2819,63 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_compute_distance_packed() {
+        let (mut client, mut server) = setup(TEST_PARAM);
+        let data = vec![
+            vec![0, 1, 0, 0u64],
+            vec![0, 0, 1, 3u64],
+            vec![2, 0, 0, 0u64],
+            vec![1, 1, 0, 1u64],
+        ];
+        let target = vec![2, 0, 1, 1u64];
+        server.set_data(&data).unwrap();
+        let (glwe, lwe) = client.make_query(&target);
+
+        let plain = server.compute_distances(&glwe, &lwe);
+        server.set_distance_mode(DistanceMode::Packed);
+        let packed = server.compute_distances(&glwe, &lwe);
+
+        let expected = crate::clear_knn::distances(&data, &target);
+        assert_eq!(packed.len(), expected.len());
+        for ((p, q), e) in packed.iter().zip(plain.iter()).zip(expected) {
+            assert_eq!(client.key.decrypt(p), e);
+            assert_eq!(client.key.decrypt(q), e);
+        }
+    }
+
+    #[test]
+    fn test_packed_noise() {
+        // a full packing, so every distance gets the noise of all the coefficients
+        let (mut client, mut server) = setup(TEST_PARAM);
+        let n_rows = TEST_PARAM.polynomial_size.0 / 4;
+        let data: Vec<_> = (0..n_rows as u64)
+            .map(|i| vec![i % 3, (i / 3) % 2, (i / 6) % 2, (i / 12) % 2])
+            .collect();
+        server.set_data(&data).unwrap();
+        server.set_distance_mode(DistanceMode::Packed);
+        assert_eq!(server.rows_per_packing(), n_rows);
+
+        let target = vec![1, 1, 0, 1u64];
+        let (glwe, lwe) = client.make_query(&target);
+        let distances = server.compute_distances(&glwe, &lwe);
+
+        // the variance from `DistanceMode::Packed` with six standard deviations,
+        // it has to stay below half of the distance scaling factor
+        let norms: u64 = data.iter().flatten().map(|m| m * m).sum();
+        let variance = TEST_PARAM.lwe_modular_std_dev.0.powi(2)
+            + 4.0 * norms as f64 * TEST_PARAM.glwe_modular_std_dev.0.powi(2);
+        let bound = (6.0 * variance.sqrt()).log2() + 64.0;
+        assert!(bound < (client.dist_delta as f64 / 2.0).log2());
+
+        let expected = crate::clear_knn::distances(&data, &target);
+        assert_eq!(distances.len(), expected.len());
+        for (d, e) in distances.iter().zip(expected) {
+            assert_eq!(client.key.decrypt(d), e);
+            assert!(client.lwe_noise(d, e) < bound);
+        }
+    }
+
     #[test]
     fn test_lower_precision() {
         // we need bigger parameters for this test
@@ -989,13 +2924,13 @@ pub mod test {
         let final_modulus = server.params.message_modulus;
         let ratio = (initial_modulus.0 / final_modulus.0) as u64;
 
-        for i in 0..8u64 {
-            // 8*8 = 64 (initial_modulus)
+        for i in 0..6u64 {
+            // the model distance 5*5 must be smaller than 64 / 2 (initial_modulus)
             for j in 0..4 {
-                // we want the maximum to be 7*7 + 3*3 < 64
+                // the maximum is 5*5 + 3*3 < 64
                 let data = vec![vec![i, 0, 0, 0u64]];
                 let target = vec![0, j, 0, 0u64];
-                server.set_data(&data);
+                server.set_data(&data).unwrap();
                 let (glwe, lwe) = client.make_query(&target);
                 let distances = server.compute_distances(&glwe, &lwe);
 
@@ -1010,6 +2945,66 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_radix_base() {
+        assert_eq!(radix_base(16, 2), 16);
+        assert_eq!(radix_base(64, 3), 8);
+        assert_eq!(radix_base(63, 3), 7);
+        assert_eq!(radix_base(1 << 40, 5), 1 << 10);
+        assert_eq!(radix_base(u64::MAX, 3), u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_radix_distances() {
+        // the ratio is 8, so two blocks in base 8 and the lower block has an offset of 4
+        let (mut client, mut server) = setup_with_modulus(TEST_PARAM, 256);
+        server.set_distance_blocks(2).unwrap();
+        server.set_labels(&[0]).unwrap();
+        for (i, j) in [(0u64, 0u64), (3, 0), (3, 2), (4, 4), (7, 5)] {
+            let data = vec![vec![i, 0, 0, 0u64]];
+            let target = vec![0, j, 0, 0u64];
+            server.set_data(&data).unwrap();
+            let (glwe, lwe) = client.make_query(&target);
+            let items = server.compute_distances_with_labels(&glwe, &lwe);
+
+            let d = i * i + j * j;
+            let actual: Vec<_> = items[0]
+                .value_blocks()
+                .into_iter()
+                .map(|ct| client.key.decrypt(ct))
+                .collect();
+            assert_eq!(actual, vec![d / 8, d % 8 + 4]);
+        }
+
+        // 9, 11 and 13 are the same after `lower_precision` but not with two blocks
+        let data = vec![
+            vec![3, 2, 0, 0u64],
+            vec![3, 0, 0, 0],
+            vec![4, 2, 0, 0],
+            vec![3, 1, 1, 0],
+        ];
+        server.set_data(&data).unwrap();
+        server.set_labels(&[0, 1, 2, 3]).unwrap();
+        let target = vec![0, 0, 0, 0u64];
+        let (glwe, lwe) = client.make_query(&target);
+        let items: Vec<_> = server
+            .compute_distances_with_labels(&glwe, &lwe)
+            .into_iter()
+            .map(|item| Arc::new(Mutex::new(item)))
+            .collect();
+
+        let server = Arc::new(RwLock::new(server));
+        let cmp = AsyncEncComparator::new(server, TEST_PARAM);
+        let sorter = BatcherSort::par_new_k(2, cmp, false);
+        sorter.par_sort(&items);
+
+        let actual: Vec<_> = items[..2]
+            .iter()
+            .map(|item| client.key.decrypt(item.lock().unwrap().class()))
+            .collect();
+        assert_eq!(actual, vec![1, 3]);
+    }
+
     #[test]
     fn test_fft() {
         let fft = Fft::new(TEST_PARAM.polynomial_size);
@@ -1043,6 +3038,40 @@ pub mod test {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_fft_pool() {
+        let n = TEST_PARAM.polynomial_size.0;
+        let input1 = Polynomial::from_container({
+            (0..n)
+                .map(|_| rand::random::<u16>() as u64)
+                .collect::<Vec<_>>()
+        });
+        let input2 = Polynomial::from_container({
+            (0..n)
+                .map(|_| rand::random::<u16>() as u64)
+                .collect::<Vec<_>>()
+        });
+        let mut expected = Polynomial::new(0u64, PolynomialSize(n));
+        polynomial_wrapping_mul(&mut expected, &input1, &input2);
+
+        // the second call reuses the context from the first one
+        // and the nested call needs a context of its own
+        for _ in 0..2 {
+            let (outer, inner) = with_polymul_fft(TEST_PARAM, |fft, stack| {
+                let mut outer = Polynomial::new(0u64, PolynomialSize(n));
+                polynomial_fft_wrapping_mul(&mut outer, &input1, &input2, fft, stack);
+                let inner = with_polymul_fft(TEST_PARAM, |fft, stack| {
+                    let mut inner = Polynomial::new(0u64, PolynomialSize(n));
+                    polynomial_fft_wrapping_mul(&mut inner, &input1, &input2, fft, stack);
+                    inner
+                });
+                (outer, inner)
+            });
+            assert_eq!(outer, expected);
+            assert_eq!(inner, expected);
+        }
+    }
+
     #[test]
     fn test_decomposition() {
         let params = TEST_PARAM;